@@ -1,6 +1,8 @@
+use crate::dicomrs_settings::ClientAETitle;
 use crate::error::name_of;
 use dicom::core::Tag;
 use dicom::ul::pdu::AbortRQSource;
+use std::time::Duration;
 
 /// Error which might happen while receiving a DICOM series.
 #[derive(thiserror::Error, Debug)]
@@ -14,9 +16,30 @@ pub(crate) enum AssociationError {
     #[error("Failed to read incoming DICOM command")]
     FailedToReadCommand(dicom::object::ReadError),
 
+    #[error("AE title is not allowed: {0}")]
+    RejectedAeTitle(ClientAETitle),
+
+    #[error("Too many concurrent associations from: {0}")]
+    TooManyAssociations(ClientAETitle),
+
+    #[error("Only {available} bytes of storage available, at least {required} are required")]
+    InsufficientStorage { available: u64, required: u64 },
+
+    #[error("DICOM instance is larger than {0} bytes")]
+    InstanceTooLarge(usize),
+
     #[error("Aborted connection from: {0:?}")]
     Aborted(AbortRQSource),
 
+    #[error("No PDU received for {0:?}")]
+    Timeout(Duration),
+
+    #[error("Association lasted longer than {0:?}")]
+    DurationExceeded(Duration),
+
+    #[error("Association has DICOM instances of more than {0} series")]
+    TooManySeries(usize),
+
     #[error("Unhandled PDU: {0}")]
     UnhandledPdu(String),
 
@@ -38,6 +61,12 @@ pub(crate) enum AssociationError {
     #[error("Failed to read DICOM data object")]
     FailedToReadObject(#[from] dicom::object::ReadError),
 
+    #[error("Failed to write DICOM data to spool file")]
+    Spool(#[from] crate::spool::SpoolError),
+
+    #[error("Failed to read spooled DICOM file")]
+    FailedToOpenSpoolFile(dicom::object::ReadError),
+
     #[error("failed to build DICOM meta file information")]
     FailedToBuildMeta(dicom::object::meta::Error),
 }
@@ -1,20 +1,68 @@
 //! Functionality related to tracking the state of series being received
 //! and writing DICOM objects to files.
 use crate::dicomrs_settings::{ClientAETitle, OurAETitle};
-use crate::enums::{AssociationEvent, PendingRegistration};
+use crate::enums::{AssociationEvent, PendingRegistration, StorageTask};
 use crate::error::{DicomRequiredTagError, DicomStorageError, HandleLoopError};
-use crate::findscu::FindScuParameters;
-use crate::pacs_file::{BadTag, PacsFileRegistration, PacsFileRegistrationRequest};
+use crate::findscu::{FindScuOptions, FindScuParameters};
+use crate::findscu_cache::{FindScuCache, PacsSeriesInfo};
+use crate::manifest::{ManifestInstance, PendingManifest, SeriesManifest};
+use crate::metrics::metrics;
+use crate::pacs_file::{
+    tt, BadTag, PacsFileOptions, PacsFileRegistration, PacsFileRegistrationRequest, StoredData,
+};
+use crate::pacs_limiter::FindScuLimiter;
+use crate::path_template::is_empty_component;
+use crate::redact::{redacted, redacted_message};
+use crate::run_everything::RunStats;
+use crate::sanitize::sanitize_path;
+use crate::seen_index::SeenIndex;
 use crate::series_key_set::SeriesKeySet;
-use camino::{Utf8Path, Utf8PathBuf};
+use crate::storage::{remove_spooled, DicomStorage, OnExisting, StoreOptions, REJECTED_DIR_NAME};
+use dicom::dictionary_std::tags;
 use dicom::object::DefaultDicomObject;
-use std::collections::HashMap;
+use futures::future;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use opentelemetry::trace::{Span, Status, Tracer};
+use opentelemetry::{global, KeyValue};
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::mpsc::error::SendError;
-use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
-use tokio::task::JoinHandle;
+use tokio::sync::mpsc::{Receiver, UnboundedSender};
+use tokio::sync::OwnedSemaphorePermit;
 use ulid::Ulid;
 
+/// Settings of [association_series_state_loop].
+pub(crate) struct HandlerOptions {
+    /// Settings for the paths and metadata of received DICOM instances.
+    pub pacs_file: Arc<PacsFileOptions>,
+    /// If true, DICOM instances are counted and logged, but nothing is stored nor registered.
+    pub dry_run: bool,
+    /// Settings for storing DICOM instances.
+    pub store: StoreOptions,
+    /// DICOM instances which are in the index are not stored again.
+    pub seen_index: Option<Arc<SeenIndex>>,
+    /// If true, a [SeriesManifest] is written for each series at the end of every association.
+    pub write_manifest: bool,
+    /// Label included in every [SeriesManifest].
+    pub label: Option<String>,
+    /// Associations which have not had any event for this long are finished as if they failed,
+    /// e.g. because their listener thread panicked before sending [AssociationEvent::Finish].
+    pub idle_timeout: Option<Duration>,
+}
+
+/// Settings and state of the C-FIND requests made by [association_series_state_loop].
+pub(crate) struct FindScu {
+    /// Settings for C-FIND requests. If [None], the PACS is never queried.
+    pub options: Option<FindScuOptions>,
+    /// Results of previous C-FIND requests.
+    pub cache: FindScuCache,
+    /// Limit of concurrent C-FIND requests to each PACS.
+    pub limiter: FindScuLimiter,
+}
+
 /// Stateful handling of [AssociationEvent].
 ///
 /// Most importantly, it writes received DICOM instances to files in storage.
@@ -29,28 +77,75 @@ use ulid::Ulid;
 ///   instance as a DICOM file.
 /// - At the end of every association, create all the `OxidicomAttemptedPushCount` files for each
 ///   series of the finished association, and finally send [PendingRegistration::End].
+///
+/// If [FindScu::options] is [None], the PACS is never queried, and `NumberOfSeriesRelatedInstances`
+/// is the number of instances received, created at the end of the association.
+/// See [HandlerOptions] for the other settings.
+///
+/// Returns the numbers of associations started, of DICOM instances accepted and of series
+/// received. The other numbers are left as 0.
 pub(crate) async fn association_series_state_loop(
-    mut receiver: UnboundedReceiver<AssociationEvent>,
-    sender: UnboundedSender<(SeriesKeySet, PendingRegistration)>,
-    files_root: Utf8PathBuf,
-) -> Result<Result<(), HandleLoopError>, SendError<(SeriesKeySet, PendingRegistration)>> {
+    mut receiver: Receiver<AssociationEvent>,
+    sender: UnboundedSender<(Ulid, SeriesKeySet, PendingRegistration)>,
+    options: HandlerOptions,
+    mut findscu: FindScu,
+) -> Result<Result<RunStats, HandleLoopError>, SendError<(Ulid, SeriesKeySet, PendingRegistration)>>
+{
     let mut inflight_associations: HashMap<Ulid, Association> = Default::default();
+    let mut idle_finished: HashSet<Ulid> = Default::default();
     let mut everything_ok = true;
-    let files_root = Arc::new(files_root);
-    while let Some(event) = receiver.recv().await {
-        match match_event(event, &mut inflight_associations, &files_root) {
-            Ok(messages) => {
-                for message in messages {
-                    sender.send(message)?
+    let mut stats = RunStats::default();
+    let mut sweep = options.idle_timeout.map(tokio::time::interval);
+    loop {
+        let events = tokio::select! {
+            event = receiver.recv() => match event {
+                Some(event) => vec![event],
+                None => break,
+            },
+            _ = tick(&mut sweep) => {
+                idle_associations(&inflight_associations, options.idle_timeout.unwrap())
+                    .into_iter()
+                    .inspect(|ulid| {
+                        idle_finished.insert(*ulid);
+                    })
+                    .map(|ulid| AssociationEvent::Finish { ulid, ok: false, permit: None })
+                    .collect()
+            }
+        };
+        for event in events {
+            match &event {
+                AssociationEvent::Start { .. } => stats.associations += 1,
+                AssociationEvent::Finish { ulid, .. } => {
+                    stats.instances += inflight_associations
+                        .get(ulid)
+                        .map(Association::instances)
+                        .unwrap_or(0)
                 }
+                _ => (),
             }
-            Err(_) => {
-                everything_ok = false;
+            match match_event(
+                event,
+                &mut inflight_associations,
+                &mut idle_finished,
+                &options,
+                &mut findscu,
+            ) {
+                Ok(messages) => {
+                    for message in messages {
+                        if matches!(message.2, PendingRegistration::End(_)) {
+                            stats.series += 1;
+                        }
+                        sender.send(message)?
+                    }
+                }
+                Err(_) => {
+                    everything_ok = false;
+                }
             }
         }
     }
     let result = if everything_ok {
-        Ok(())
+        Ok(stats)
     } else {
         Err(HandleLoopError(
             "There was an error processing DICOM objects.",
@@ -59,192 +154,648 @@ pub(crate) async fn association_series_state_loop(
     Ok(result)
 }
 
+/// Wait for the next tick of `interval`, or forever if there is none.
+async fn tick(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Find the associations which have not had any event for longer than `idle_timeout`.
+fn idle_associations(
+    inflight_associations: &HashMap<Ulid, Association>,
+    idle_timeout: Duration,
+) -> Vec<Ulid> {
+    inflight_associations
+        .iter()
+        .filter(|(_, association)| association.updated.elapsed() > idle_timeout)
+        .map(|(ulid, association)| {
+            tracing::warn!(
+                association_ulid = ulid.to_string(),
+                peer_address = association.peer().as_deref(),
+                idle_seconds = association.updated.elapsed().as_secs(),
+                "No events from the association, it will be finished."
+            );
+            *ulid
+        })
+        .collect()
+}
+
 /// Helper function which handles most of what [association_series_state_loop] is supposed to do.
 ///
 /// Since this function is not async, it helps to protect the invariant that
 /// [PendingRegistration::End] will be the last sent message of a series (there is no async
 /// code to cause a race condition).
+///
+/// `idle_finished` are the associations which were finished because of
+/// [HandlerOptions::idle_timeout]. Their events are ignored until their real
+/// [AssociationEvent::Finish].
 fn match_event(
     event: AssociationEvent,
     inflight_associations: &mut HashMap<Ulid, Association>,
-    files_root: &Arc<Utf8PathBuf>,
-) -> Result<Vec<(SeriesKeySet, PendingRegistration)>, ()> {
+    idle_finished: &mut HashSet<Ulid>,
+    options: &HandlerOptions,
+    findscu: &mut FindScu,
+) -> Result<Vec<(Ulid, SeriesKeySet, PendingRegistration)>, ()> {
     match event {
         AssociationEvent::Start {
             ulid,
             aec,
             aet,
+            pacs_name,
             pacs_address,
+            peer_address,
+            storage,
         } => {
-            if pacs_address.is_none() {
+            if pacs_address.is_none() && findscu.options.is_some() {
                 tracing::warn!(
                     association_ulid = ulid.to_string(),
                     "OXIDICOM_PACS_ADDRESS not configured for this association."
                 );
             }
-            inflight_associations.insert(ulid, Association::new(aec, aet, pacs_address));
+            if inflight_associations.contains_key(&ulid) {
+                tracing::error!(
+                    association_ulid = ulid.to_string(),
+                    "Duplicate association ULID, the association will not be tracked."
+                );
+                return Ok(Vec::with_capacity(0));
+            }
+            metrics().associations_started.inc();
+            inflight_associations.insert(
+                ulid,
+                Association::new(aec, aet, pacs_name, pacs_address, peer_address, storage),
+            );
             Ok(Vec::with_capacity(0))
         }
-        AssociationEvent::DicomInstance { ulid, dcm } => {
-            match receive_dicom_instance(ulid, dcm, inflight_associations, &files_root) {
+        AssociationEvent::DicomInstance {
+            ulid,
+            dcm,
+            storage,
+            context,
+        } => {
+            metrics().instances_received.inc();
+            let Some(association) = inflight_associations.get_mut(&ulid) else {
+                if idle_finished.contains(&ulid) {
+                    tracing::warn!(
+                        association_ulid = ulid.to_string(),
+                        "DICOM instance received after the association was finished for being idle, it will not be stored."
+                    );
+                } else {
+                    tracing::error!(
+                        association_ulid = ulid.to_string(),
+                        "DICOM instance received for an unknown association, it will not be stored."
+                    );
+                }
+                storage.discard();
+                return Ok(Vec::with_capacity(0));
+            };
+            association.updated = Instant::now();
+            match receive_dicom_instance(ulid, dcm, storage, context, association, options, findscu)
+            {
                 Ok((series, tasks)) => {
+                    let storage = &association.storage;
                     let pending_tasks = tasks
                         .into_iter()
-                        .map(PendingRegistration::Task)
-                        .map(|task| (series.clone(), task))
+                        .map(|task| PendingRegistration::Task(task, Arc::clone(storage)))
+                        .map(|task| (ulid, series.clone(), task))
                         .collect();
                     Ok(pending_tasks)
                 }
                 Err(e) => {
-                    tracing::error!(association_ulid = ulid.to_string(), message = e.to_string());
+                    tracing::error!(
+                        association_ulid = ulid.to_string(),
+                        peer_address = association.peer().as_deref(),
+                        message = e.to_string()
+                    );
                     Err(())
                 }
             }
         }
-        AssociationEvent::Finish { ulid, .. } => {
-            let association = inflight_associations
-                .remove(&ulid)
-                .expect("Unknown association ULID");
-            Ok(finish_association(ulid, association.series, &files_root))
+        AssociationEvent::Unreadable {
+            ulid,
+            sop_instance_uid,
+            data,
+        } => {
+            let Some(association) = inflight_associations.get_mut(&ulid) else {
+                if idle_finished.contains(&ulid) {
+                    tracing::warn!(
+                        association_ulid = ulid.to_string(),
+                        "Unreadable DICOM instance received after the association was finished for being idle, it will not be saved."
+                    );
+                } else {
+                    tracing::error!(
+                        association_ulid = ulid.to_string(),
+                        "Unreadable DICOM instance received for an unknown association, it will not be saved."
+                    );
+                }
+                data.discard();
+                return Ok(Vec::with_capacity(0));
+            };
+            association.updated = Instant::now();
+            if options.dry_run {
+                data.discard();
+            } else {
+                let path = format!(
+                    "{REJECTED_DIR_NAME}/{}/{ulid}/{}",
+                    sanitize_component(association.pacs_name.as_str(), "pacs_name"),
+                    sanitize_component(&sop_instance_uid, "SOPInstanceUID")
+                );
+                tokio::task::spawn_blocking(save_rejected(
+                    Arc::clone(&association.storage),
+                    path,
+                    data,
+                ));
+            }
+            Ok(Vec::with_capacity(0))
+        }
+        AssociationEvent::Finish { ulid, ok, permit } => {
+            let tasks = if let Some(association) = inflight_associations.remove(&ulid) {
+                metrics().associations_finished.inc();
+                if !ok && !association.series.is_empty() {
+                    // e.g. the PACS sent A-ABORT. The instances received before then are
+                    // complete and valid, so their series are finished as usual.
+                    tracing::warn!(
+                        association_ulid = ulid.to_string(),
+                        peer_address = association.peer().as_deref(),
+                        instances = association.instances(),
+                        "Association ended with an error, the DICOM instances received before it will be registered."
+                    );
+                }
+                for series in association.series.values() {
+                    metrics()
+                        .series_duration
+                        .with_label_values(&[association.aec.as_str(), &series.modality])
+                        .observe(series.started.elapsed().as_secs_f64());
+                }
+                if options.dry_run {
+                    Vec::with_capacity(0)
+                } else {
+                    finish_association(
+                        ulid,
+                        association.series,
+                        &association.storage,
+                        options.write_manifest,
+                        options.label.as_deref(),
+                        findscu.options.is_none(),
+                    )
+                }
+            } else {
+                if idle_finished.remove(&ulid) {
+                    tracing::warn!(
+                        association_ulid = ulid.to_string(),
+                        ok,
+                        "Association which was finished for being idle has ended."
+                    );
+                }
+                // otherwise, the association was rejected or failed before it was established
+                Vec::with_capacity(0)
+            };
+            // all of the association's DICOM instances were received by this point,
+            // so it no longer counts towards OXIDICOM_MAX_INFLIGHT_ASSOCIATIONS.
+            drop(permit);
+            Ok(tasks)
         }
     }
 }
 
-/// Receive a DICOM instance. It will be taken note of in `inflight_associations`.
+/// Receive a DICOM instance. It will be taken note of in its `association`.
 ///
-/// - On the first DICOM instance of a series received: try to ask the PACS server for the `NumberOfSeriesRelatedInstances`.
-/// - For every DICOM instance received: create a task to store the DICOM instance as a file
+/// - On the first DICOM instance of a series received: try to ask the PACS server for the `NumberOfSeriesRelatedInstances`,
+///   unless [FindScu::options] is [None].
+/// - For every DICOM instance received: create a task to store the DICOM instance as a file,
+///   traced as a child span of the association's `context`
+/// - If the same `SOPInstanceUID` was already received for the series during this association,
+///   the DICOM instance is skipped and no tasks are created.
+/// - If [HandlerOptions::dry_run], the DICOM instance is only logged and no tasks are created.
+/// - If the DICOM instance is in [HandlerOptions::seen_index], the storage task does not store it.
+/// - If a file already exists at the path of the DICOM instance, the storage task does
+///   what [StoreOptions::on_existing] says.
 ///
 /// The tasks are returned.
 fn receive_dicom_instance(
     ulid: Ulid,
     dcm: DefaultDicomObject,
-    inflight_associations: &mut HashMap<Ulid, Association>,
-    files_root: &Arc<Utf8PathBuf>,
-) -> Result<
-    (
-        SeriesKeySet,
-        Vec<JoinHandle<Result<PacsFileRegistrationRequest, ()>>>,
-    ),
-    DicomRequiredTagError,
-> {
-    let association = inflight_associations
-        .get_mut(&ulid)
-        .expect("Unknown association ULID");
-    let pacs_name = association.aec.clone();
-    let (pacs_file, bad_tags) = PacsFileRegistration::new(pacs_name, dcm)?;
-    report_bad_tags(&pacs_file.request, ulid, bad_tags);
+    storage: StoredData,
+    context: opentelemetry::Context,
+    association: &mut Association,
+    options: &HandlerOptions,
+    findscu: &mut FindScu,
+) -> Result<(SeriesKeySet, Vec<StorageTask>), DicomRequiredTagError> {
+    let pacs_name = association.pacs_name.clone();
+    let (mut pacs_file, bad_tags) =
+        match PacsFileRegistration::new(pacs_name, dcm, &options.pacs_file) {
+            Ok(ok) => ok,
+            Err(e) => {
+                storage.discard();
+                return Err(e);
+            }
+        };
+    pacs_file.storage = storage;
+    report_bad_tags(&pacs_file.request, ulid, association, bad_tags);
     let series_key_set = SeriesKeySet::from(pacs_file.request.clone());
-    let storage_task = {
-        let files_root = Arc::clone(files_root);
-        tokio::task::spawn_blocking(move || {
-            store_dicom(&files_root, &pacs_file).map(|_| pacs_file.request)
-        })
+    let sop_instance_uid = tt(&pacs_file.obj, tags::SOP_INSTANCE_UID)
+        .unwrap_or_default()
+        .trim_end_matches('\0')
+        .to_string();
+    let modality = pacs_file.request.Modality.clone().unwrap_or_default();
+    if association
+        .series
+        .get(&series_key_set)
+        .is_some_and(|received| received.instances.contains_key(&sop_instance_uid))
+    {
+        tracing::warn!(
+            association_ulid = ulid.to_string(),
+            SOPInstanceUID = sop_instance_uid,
+            "Duplicate DICOM instance skipped."
+        );
+        pacs_file.storage.discard();
+        return Ok((series_key_set, Vec::with_capacity(0)));
+    }
+    if options.dry_run {
+        tracing::info!(
+            event = "dry_run",
+            path = redacted(&pacs_file.request.path).as_ref()
+        );
+        pacs_file.storage.discard();
+        association
+            .series
+            .entry(series_key_set.clone())
+            .or_insert_with(|| ReceivedSeries::new(modality, future::ready(None).boxed().shared()))
+            .instances
+            .insert(sop_instance_uid, pacs_file.request.path);
+        return Ok((series_key_set, Vec::with_capacity(0)));
+    }
+    let fill_from_pacs = options.pacs_file.fill_from_pacs;
+    let (pacs_info, numrelatedinstances_task) =
+        if let Some(received) = association.series.get_mut(&series_key_set) {
+            received
+                .instances
+                .insert(sop_instance_uid.clone(), pacs_file.request.path.clone());
+            (received.pacs_info.clone(), None)
+        } else {
+            let (task, pacs_info) = if let Some(findscu_options) = findscu.options {
+                let (task, pacs_info) = start_numrelatedinstances_task(
+                    ulid,
+                    series_key_set.clone(),
+                    association,
+                    fill_from_pacs,
+                    findscu_options,
+                    &mut findscu.cache,
+                    &mut findscu.limiter,
+                );
+                (Some(task), pacs_info)
+            } else {
+                (None, future::ready(None).boxed().shared())
+            };
+            let mut received = ReceivedSeries::new(modality, pacs_info.clone());
+            received
+                .instances
+                .insert(sop_instance_uid.clone(), pacs_file.request.path.clone());
+            association.series.insert(series_key_set.clone(), received);
+            (pacs_info, task)
+        };
+    let is_missing = |value: &Option<String>| value.as_deref().unwrap_or("").is_empty();
+    let missing_series_info =
+        is_missing(&pacs_file.request.Modality) || is_missing(&pacs_file.request.SeriesDescription);
+    let storage = Arc::clone(&association.storage);
+    let store_options = options.store;
+    let store = move |pacs_file: PacsFileRegistration| {
+        let tracer = global::tracer(env!("CARGO_PKG_NAME"));
+        let mut span = tracer.start_with_context("store_dicom", &context);
+        span.set_attributes([
+            KeyValue::new("path", redacted(&pacs_file.request.path).to_string()),
+            KeyValue::new("SOPInstanceUID", sop_instance_uid),
+        ]);
+        let result = store_dicom(storage.as_ref(), &pacs_file, store_options);
+        if result.is_err() {
+            span.set_status(Status::error("Could not store DICOM instance"));
+        }
+        result.map(|_| pacs_file.request)
     };
-
-    let tasks = if let Some(count) = association.series.get_mut(&series_key_set) {
-        *count += 1;
-        vec![storage_task]
+    let storage_task = if let Some(seen_index) = &options.seen_index {
+        tokio::task::spawn(store_unless_seen(Arc::clone(seen_index), pacs_file, store))
     } else {
-        association.series.insert(series_key_set.clone(), 1);
-        let numrelatedinstances_task =
-            start_numrelatedinstances_task(ulid, series_key_set.clone(), association, files_root);
-        vec![storage_task, numrelatedinstances_task]
+        tokio::task::spawn_blocking(move || store(pacs_file).map(Some))
     };
+    let storage_task = if fill_from_pacs && missing_series_info {
+        tokio::task::spawn(fill_in_from_pacs(storage_task, pacs_info))
+    } else {
+        storage_task
+    };
+    let tasks = std::iter::once(storage_task)
+        .chain(numrelatedinstances_task)
+        .collect();
     Ok((series_key_set, tasks))
 }
 
+/// Call `store` in a blocking task, unless `pacs_file` is in `seen_index`, in which case
+/// [None] is returned.
+///
+/// If `seen_index` cannot be queried, the DICOM instance is stored anyway.
+async fn store_unless_seen<F>(
+    seen_index: Arc<SeenIndex>,
+    pacs_file: PacsFileRegistration,
+    store: F,
+) -> Result<Option<PacsFileRegistrationRequest>, ()>
+where
+    F: FnOnce(PacsFileRegistration) -> Result<PacsFileRegistrationRequest, ()> + Send + 'static,
+{
+    match seen_index.contains(&pacs_file.request.path).await {
+        Ok(true) => {
+            tracing::info!(
+                event = "seen",
+                path = redacted(&pacs_file.request.path).as_ref()
+            );
+            pacs_file.storage.discard();
+            return Ok(None);
+        }
+        Ok(false) => (),
+        Err(e) => tracing::warn!(event = "seen_index", error = e.to_string()),
+    }
+    tokio::task::spawn_blocking(move || store(pacs_file).map(Some))
+        .await
+        .unwrap_or(Err(()))
+}
+
+/// Wait for `storage_task`, then fill in its missing `Modality` and `SeriesDescription`
+/// with the values reported by the PACS.
+async fn fill_in_from_pacs(
+    storage_task: StorageTask,
+    pacs_info: PacsSeriesInfo,
+) -> Result<Option<PacsFileRegistrationRequest>, ()> {
+    let Some(mut request) = storage_task.await.unwrap_or(Err(()))? else {
+        return Ok(None);
+    };
+    if let Some(info) = pacs_info.await {
+        let not_empty = |s: &String| !s.is_empty();
+        request.Modality = request.Modality.filter(not_empty).or(info.modality);
+        request.SeriesDescription = request
+            .SeriesDescription
+            .filter(not_empty)
+            .or(info.series_description);
+    }
+    Ok(Some(request))
+}
+
 /// Creates messages for the end of an association.
 ///
 /// For each series with one or more instance:
 ///
-/// - Create a task for creating the "Oxidicom Custom Metadata" `OxidicomAttemptedPushCount=N` file.
-/// - Create a [PendingRegistration::End]
+/// - Create a task for creating the "Oxidicom Custom Metadata" `OxidicomAttemptedPushCount=N` file,
+///   which also reports whether `N` differs from the `NumberOfSeriesRelatedInstances`.
+/// - If `trust_received_count`, create the `NumberOfSeriesRelatedInstances=N` file,
+///   since the PACS was not asked for it.
+/// - Create a [PendingRegistration::End], with a [PendingManifest] labeled with `label`
+///   if `write_manifest`.
 fn finish_association(
     ulid: Ulid,
-    series_counts: HashMap<SeriesKeySet, usize>,
-    files_root: &Arc<Utf8PathBuf>,
-) -> Vec<(SeriesKeySet, PendingRegistration)> {
-    let mut messages = Vec::with_capacity(series_counts.len() * 2);
-    for (series, count) in &series_counts {
-        let files_root = Arc::clone(files_root);
+    series_instances: HashMap<SeriesKeySet, ReceivedSeries>,
+    storage: &Arc<dyn DicomStorage>,
+    write_manifest: bool,
+    label: Option<&str>,
+    trust_received_count: bool,
+) -> Vec<(Ulid, SeriesKeySet, PendingRegistration)> {
+    let mut messages = Vec::with_capacity(series_instances.len() * 3);
+    let mut endings = Vec::with_capacity(series_instances.len());
+    for (series, received) in series_instances {
+        let task_storage = Arc::clone(storage);
+        let n_received = received.instances.len();
+        if trust_received_count {
+            let pacs_file = series.clone().into_oxidicom_custom_pacsfile(
+                ulid,
+                "NumberOfSeriesRelatedInstances",
+                n_received.to_string(),
+            );
+            let task = tokio::task::spawn(create_blank_file(Arc::clone(storage), pacs_file));
+            let task = PendingRegistration::Task(task, Arc::clone(storage));
+            messages.push((ulid, series.clone(), task));
+        }
         let pacs_file = series.clone().into_oxidicom_custom_pacsfile(
             ulid,
             "OxidicomAttemptedPushCount",
-            count.to_string(),
+            n_received.to_string(),
         );
-        let task =
-            tokio::task::spawn(
-                async move { create_blank_file_tokio(&files_root, pacs_file).await },
-            );
-        messages.push((series.clone(), PendingRegistration::Task(task)));
+        let pacs_info = received.pacs_info.clone();
+        let series_instance_uid = series.SeriesInstanceUID.to_string();
+        let task = tokio::task::spawn(async move {
+            let expected = pacs_info
+                .await
+                .map(|info| info.number_of_series_related_instances);
+            if let Some(expected) = expected {
+                report_received_count(ulid, series_instance_uid, expected, n_received);
+            }
+            create_blank_file(task_storage, pacs_file).await
+        });
+        let task = PendingRegistration::Task(task, Arc::clone(storage));
+        messages.push((ulid, series.clone(), task));
+        let manifest =
+            write_manifest.then(|| new_manifest(ulid, &series, received, storage, label));
+        endings.push((ulid, series, PendingRegistration::End(manifest)));
     }
-    let endings = series_counts
-        .into_iter()
-        .map(|(series, _count)| (series, PendingRegistration::End));
     messages.extend(endings);
     messages
 }
 
+/// Create the [PendingManifest] of a series received during an association.
+fn new_manifest(
+    ulid: Ulid,
+    series: &SeriesKeySet,
+    received: ReceivedSeries,
+    storage: &Arc<dyn DicomStorage>,
+    label: Option<&str>,
+) -> PendingManifest {
+    let mut instances: Vec<_> = received
+        .instances
+        .into_iter()
+        .map(|(sop_instance_uid, path)| ManifestInstance {
+            SOPInstanceUID: sop_instance_uid,
+            path,
+        })
+        .collect();
+    instances.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+    let manifest = SeriesManifest {
+        association_ulid: ulid.to_string(),
+        pacs_name: series.pacs_name.to_string(),
+        label: label.map(String::from),
+        StudyInstanceUID: series.StudyInstanceUID.to_string(),
+        SeriesInstanceUID: series.SeriesInstanceUID.to_string(),
+        ndicom: instances.len(),
+        NumberOfSeriesRelatedInstances: None,
+        instances,
+    };
+    PendingManifest {
+        path: format!("{}/{}", series.dir_path, SeriesManifest::file_name(ulid)),
+        manifest,
+        pacs_info: received.pacs_info,
+        storage: Arc::clone(storage),
+    }
+}
+
+/// [sanitize_path], or `name` if the result would be an empty, `.` or `..` path component,
+/// like how [crate::path_template::PathTemplate::render] handles such values.
+fn sanitize_component(value: &str, name: &str) -> String {
+    let sanitized = sanitize_path(value);
+    if is_empty_component(&sanitized) {
+        name.to_string()
+    } else {
+        sanitized
+    }
+}
+
+/// Write the data of a DICOM instance which could not be read to storage. Errors are logged.
+fn save_rejected(
+    storage: Arc<dyn DicomStorage>,
+    path: String,
+    data: StoredData,
+) -> impl FnOnce() + Send + 'static {
+    move || {
+        let result = match data {
+            StoredData::Spooled(spooled) => storage.write_spooled(&path, &spooled),
+            StoredData::Raw(data) => storage.write_file(&path, &data),
+            // there is no received data to save
+            StoredData::Encode => storage.write_file(&path, &[]),
+        };
+        match result {
+            Ok(location) => tracing::info!(event = "rejected", path = redacted(&location).as_ref()),
+            Err(e) => tracing::error!(
+                event = "rejected",
+                error = redacted_message(&e.to_string()).as_ref()
+            ),
+        }
+    }
+}
+
 /// Create a blank file in place of the [PacsFileRegistrationRequest], and return it if successful.
 ///
 /// Intended to be used for creating "Oxidicom Custom Metadata" files.
-fn create_blank_file(
-    files_root: &Utf8Path,
+async fn create_blank_file(
+    storage: Arc<dyn DicomStorage>,
     pacs_file: PacsFileRegistrationRequest,
-) -> Result<PacsFileRegistrationRequest, ()> {
-    let path = files_root.join(&pacs_file.path);
-    if let Some(parent) = path.parent() {
-        fs_err::create_dir_all(parent).map_err(|e| {
-            tracing::error!(path = parent.as_str(), message = e.to_string());
-        })?
-    }
-    fs_err::File::create(&path).map(|_| pacs_file).map_err(|e| {
-        tracing::error!(path = path.into_string(), message = e.to_string());
+) -> Result<Option<PacsFileRegistrationRequest>, ()> {
+    tokio::task::spawn_blocking(move || {
+        storage
+            .write_file(&pacs_file.path, &[])
+            .map(|_| Some(pacs_file))
+            .map_err(|e| tracing::error!(message = redacted_message(&e.to_string()).as_ref()))
     })
-}
-
-/// Async version of [create_blank_file].
-async fn create_blank_file_tokio(
-    files_root: &Utf8Path,
-    pacs_file: PacsFileRegistrationRequest,
-) -> Result<PacsFileRegistrationRequest, ()> {
-    let path = files_root.join(&pacs_file.path);
-    if let Some(parent) = path.parent() {
-        fs_err::tokio::create_dir_all(parent).await.map_err(|e| {
-            tracing::error!(path = parent.as_str(), message = e.to_string());
-        })?
-    }
-    fs_err::tokio::File::create(&path)
-        .await
-        .map(|_| pacs_file)
-        .map_err(|e| {
-            tracing::error!(path = path.into_string(), message = e.to_string());
-        })
+    .await
+    .unwrap_or(Err(()))
 }
 
 /// Start a task for producing the "Oxidicom Custom Metadata" `NumberOfSeriesRelatedInstances=N` file.
+///
+/// Also returns what the PACS reported about the series, which is [None] if it could not be obtained.
+/// If `extra_keys`, `Modality` and `SeriesDescription` are also requested from the PACS.
+/// A recent result for the same series from the same PACS is reused from `cache`.
+/// C-FINDs to the same PACS are limited by `limiter`.
 fn start_numrelatedinstances_task(
     ulid: Ulid,
     series_key_set: SeriesKeySet,
     association: &Association,
-    files_root: &Arc<Utf8PathBuf>,
-) -> JoinHandle<Result<PacsFileRegistrationRequest, ()>> {
-    let findscu_params = maybe_findscu(ulid, &series_key_set, association);
-    let series_key_set = series_key_set.clone();
-    let files_root = Arc::clone(files_root);
-    tokio::task::spawn_blocking(move || {
-        let value = findscu_params
-            .and_then(|p| p.get_number_of_series_related_instances().ok())
-            .map(|n| n.to_string())
-            .unwrap_or_else(|| "unknown".to_string());
-        let pacs_file = series_key_set.into_oxidicom_custom_pacsfile(
-            ulid,
-            "NumberOfSeriesRelatedInstances",
-            value,
+    extra_keys: bool,
+    options: FindScuOptions,
+    cache: &mut FindScuCache,
+    limiter: &mut FindScuLimiter,
+) -> (StorageTask, PacsSeriesInfo) {
+    let findscu_params = maybe_findscu(ulid, &series_key_set, association, extra_keys, options);
+    let storage = Arc::clone(&association.storage);
+    let pacs_info = if let Some(pacs_address) = &association.pacs_address {
+        cache.get_or_insert_with(pacs_address, &series_key_set.SeriesInstanceUID, || {
+            let permit = limiter.acquire(pacs_address);
+            findscu_series_info(findscu_params, options, permit)
+        })
+    } else {
+        findscu_series_info(findscu_params, options, future::ready(None).boxed())
+    };
+    let task = {
+        let pacs_info = pacs_info.clone();
+        tokio::task::spawn(async move {
+            let value = pacs_info
+                .await
+                .map(|info| info.number_of_series_related_instances.to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+            let pacs_file = series_key_set.into_oxidicom_custom_pacsfile(
+                ulid,
+                "NumberOfSeriesRelatedInstances",
+                value,
+            );
+            create_blank_file(storage, pacs_file).await
+        })
+    };
+    (task, pacs_info)
+}
+
+/// Do a C-FIND in a blocking task, giving up after [FindScuOptions::deadline].
+///
+/// The C-FIND starts after `permit` is acquired and [FindScuOptions::wait_time] has passed.
+fn findscu_series_info(
+    findscu_params: Option<FindScuParameters>,
+    options: FindScuOptions,
+    permit: BoxFuture<'static, Option<OwnedSemaphorePermit>>,
+) -> PacsSeriesInfo {
+    async move {
+        let findscu_params = findscu_params?;
+        let permit = permit.await;
+        tokio::time::sleep(options.wait_time()).await;
+        // The permit is held by the blocking task, so that a C-FIND which is still running
+        // after the deadline keeps counting towards the limit of concurrent C-FINDs.
+        let findscu = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            findscu_params.get_series_info().ok()
+        });
+        // A PACS which accepts the TCP connection might never respond to the association
+        // request, so the C-FIND might still be running after the deadline.
+        // In that case, the series is handled as if the PACS is unreachable.
+        let result = if let Some(deadline) = options.deadline() {
+            tokio::time::timeout(deadline, findscu).await.ok()
+        } else {
+            Some(findscu.await)
+        };
+        result.and_then(|r| r.ok()).flatten()
+    }
+    .boxed()
+    .shared()
+}
+
+/// Report whether the number of DICOM instances received for a series in an association
+/// matches the `NumberOfSeriesRelatedInstances` reported by the PACS.
+fn report_received_count(
+    ulid: Ulid,
+    series_instance_uid: String,
+    expected: usize,
+    received: usize,
+) {
+    let tracer = global::tracer(env!("CARGO_PKG_NAME"));
+    let mut span = tracer.start("received_series");
+    span.set_attributes([
+        KeyValue::new("association_ulid", ulid.to_string()),
+        KeyValue::new("SeriesInstanceUID", series_instance_uid.clone()),
+        KeyValue::new("NumberOfSeriesRelatedInstances", expected as i64),
+        KeyValue::new("OxidicomAttemptedPushCount", received as i64),
+    ]);
+    if received > expected {
+        // duplicates are not counted, so the PACS sent instances which it does not report
+        tracing::error!(
+            association_ulid = ulid.to_string(),
+            SeriesInstanceUID = series_instance_uid,
+            NumberOfSeriesRelatedInstances = expected,
+            OxidicomAttemptedPushCount = received,
+            "More DICOM instances received than the PACS reported."
         );
-        create_blank_file(&files_root, pacs_file)
-    })
+        span.set_status(Status::error("more than NumberOfSeriesRelatedInstances"));
+    } else if expected != received {
+        tracing::warn!(
+            association_ulid = ulid.to_string(),
+            SeriesInstanceUID = series_instance_uid,
+            NumberOfSeriesRelatedInstances = expected,
+            OxidicomAttemptedPushCount = received,
+            "Number of DICOM instances received differs from what the PACS reported."
+        );
+        span.set_status(Status::error("NumberOfSeriesRelatedInstances mismatch"));
+    }
 }
 
 /// If [Association::pacs_address] is [Some], create and return [FindScuParameters].
@@ -252,6 +803,8 @@ fn maybe_findscu(
     ulid: Ulid,
     series_key_set: &SeriesKeySet,
     association: &Association,
+    extra_keys: bool,
+    options: FindScuOptions,
 ) -> Option<FindScuParameters> {
     if let Some(pacs_address) = &association.pacs_address {
         let findscu_params = FindScuParameters {
@@ -261,6 +814,8 @@ fn maybe_findscu(
             aet: association.aet.clone(),
             study_instance_uid: series_key_set.StudyInstanceUID.to_string(),
             series_instance_uid: series_key_set.SeriesInstanceUID.to_string(),
+            extra_keys,
+            options,
         };
         Some(findscu_params)
     } else {
@@ -275,54 +830,165 @@ struct Association {
     aec: ClientAETitle,
     /// Our AE title
     aet: OurAETitle,
+    /// `pacs_name` of the received files
+    pacs_name: ClientAETitle,
     /// Address where we are receiving DICOMs from
     pacs_address: Option<String>,
+    /// Address the association was made from, to find which device sent bad data
+    peer_address: Option<SocketAddr>,
+    /// Storage of the listener which accepted the association
+    storage: Arc<dyn DicomStorage>,
     /// The unique series we are receiving during this association.
     /// Typically, in _ChRIS_ one series will be pulled per association. However,
     /// it is possible for a PACS server to push any number or fraction of a series to us.
-    series: HashMap<SeriesKeySet, usize>,
+    ///
+    series: HashMap<SeriesKeySet, ReceivedSeries>,
+
+    /// When the last event of this association was received
+    updated: Instant,
+}
+
+/// A series being received during an association.
+struct ReceivedSeries {
+    /// The `SOPInstanceUID`s received and their paths, remembered so that duplicates can be skipped
+    instances: HashMap<String, String>,
+    /// What the PACS says about the series, e.g. how many instances it has
+    pacs_info: PacsSeriesInfo,
+    /// `Modality` of the first instance received
+    modality: String,
+    /// When the first instance was received
+    started: Instant,
+}
+
+impl ReceivedSeries {
+    fn new(modality: String, pacs_info: PacsSeriesInfo) -> Self {
+        Self {
+            instances: Default::default(),
+            pacs_info,
+            modality,
+            started: Instant::now(),
+        }
+    }
 }
 
 impl Association {
-    fn new(aec: ClientAETitle, aet: OurAETitle, pacs_address: Option<String>) -> Self {
+    fn new(
+        aec: ClientAETitle,
+        aet: OurAETitle,
+        pacs_name: ClientAETitle,
+        pacs_address: Option<String>,
+        peer_address: Option<SocketAddr>,
+        storage: Arc<dyn DicomStorage>,
+    ) -> Self {
         Self {
             aec,
             aet,
+            pacs_name,
             pacs_address,
+            peer_address,
+            storage,
             series: Default::default(),
+            updated: Instant::now(),
         }
     }
+
+    /// [Association::peer_address] for logging.
+    fn peer(&self) -> Option<String> {
+        self.peer_address.map(|address| address.to_string())
+    }
+
+    /// Number of DICOM instances received, not counting duplicates.
+    fn instances(&self) -> usize {
+        self.series
+            .values()
+            .map(|series| series.instances.len())
+            .sum()
+    }
 }
 
-/// Wraps [write_dicom] with OpenTelemetry logging.
-fn store_dicom(files_root: &Utf8Path, pacs_file: &PacsFileRegistration) -> Result<(), ()> {
-    match write_dicom(pacs_file, files_root) {
-        Ok(path) => tracing::info!(event = "storage", path = path.into_string()),
+/// Wraps [write_unless_existing] and [verify_written] with OpenTelemetry logging.
+fn store_dicom(
+    storage: &dyn DicomStorage,
+    pacs_file: &PacsFileRegistration,
+    options: StoreOptions,
+) -> Result<(), ()> {
+    let result =
+        write_unless_existing(storage, pacs_file, options.on_existing).and_then(|location| {
+            if options.verify_write && location.is_some() {
+                verify_written(storage, pacs_file)?;
+            }
+            Ok(location)
+        });
+    match result {
+        Ok(Some(location)) => {
+            tracing::info!(event = "storage", path = redacted(&location).as_ref())
+        }
+        Ok(None) => tracing::info!(
+            event = "existing",
+            path = redacted(&pacs_file.request.path).as_ref()
+        ),
         Err(e) => {
-            tracing::error!(event = "storage", error = e.to_string());
+            tracing::error!(
+                event = "storage",
+                error = redacted_message(&e.to_string()).as_ref()
+            );
             return Err(());
         }
     }
     Ok(())
 }
 
-/// Write a DICOM object to the filesystem.
-fn write_dicom<P: AsRef<Utf8Path>>(
+/// Call [DicomStorage::write_dicom], unless a file already exists at the path of `pacs_file`
+/// and `on_existing` is not [OnExisting::Overwrite]. Returns where the file was written,
+/// or `None` if the existing file was kept.
+fn write_unless_existing(
+    storage: &dyn DicomStorage,
     pacs_file: &PacsFileRegistration,
-    files_root: P,
-) -> Result<Utf8PathBuf, DicomStorageError> {
-    let output_path = files_root.as_ref().join(&pacs_file.request.path);
-    if let Some(parent_dir) = output_path.parent() {
-        fs_err::create_dir_all(parent_dir)?;
-    }
-    pacs_file.obj.write_to_file(&output_path)?;
-    Ok(output_path)
+    on_existing: OnExisting,
+) -> Result<Option<String>, DicomStorageError> {
+    let exists = on_existing != OnExisting::Overwrite
+        && storage
+            .exists(&pacs_file.request.path)
+            .inspect_err(|_| remove_spooled(pacs_file))?;
+    if !exists {
+        return storage.write_dicom(pacs_file).map(Some);
+    }
+    remove_spooled(pacs_file);
+    match on_existing {
+        OnExisting::Error => Err(DicomStorageError::AlreadyExists(
+            pacs_file.request.path.clone(),
+        )),
+        _ => Ok(None),
+    }
+}
+
+/// Read back the file of `pacs_file` from `storage`, and check that its `SOPInstanceUID`
+/// is the one which was written.
+fn verify_written(
+    storage: &dyn DicomStorage,
+    pacs_file: &PacsFileRegistration,
+) -> Result<(), DicomStorageError> {
+    let sop_instance_uid =
+        |dcm| tt(dcm, tags::SOP_INSTANCE_UID).map(|s| s.trim_end_matches('\0').to_string());
+    let written = storage.read_header(&pacs_file.request.path)?;
+    let expected = sop_instance_uid(&pacs_file.obj).unwrap_or_default();
+    let actual = sop_instance_uid(&written).unwrap_or_default();
+    if actual == expected {
+        Ok(())
+    } else {
+        Err(DicomStorageError::VerifyMismatch {
+            path: pacs_file.request.path.clone(),
+            expected,
+            actual,
+        })
+    }
 }
 
 /// Report bad tags via OpenTelemetry.
 fn report_bad_tags<T: AsRef<[BadTag]>>(
     pacs_file: &PacsFileRegistrationRequest,
     ulid: Ulid,
+    association: &Association,
     bad_tags: T,
 ) {
     let bad_tags_slice = bad_tags.as_ref();
@@ -336,7 +1002,409 @@ fn report_bad_tags<T: AsRef<[BadTag]>>(
         .join(",");
     tracing::warn!(
         association_ulid = ulid.to_string(),
-        path = &pacs_file.path,
+        peer_address = association.peer().as_deref(),
+        pacs_name = pacs_file.pacs_name.as_str(),
+        SeriesInstanceUID = &pacs_file.SeriesInstanceUID,
+        path = redacted(&pacs_file.path).as_ref(),
         bad_tags = bad_tags_csv
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pacs_file::tests::{example_dcm, example_options};
+    use crate::storage::{Compression, FileModes, FilesystemStorage};
+    use crate::test_util::temp_dir;
+    use rstest::*;
+    use std::num::NonZeroUsize;
+
+    fn example_handler_options() -> HandlerOptions {
+        HandlerOptions {
+            pacs_file: Arc::new(example_options()),
+            dry_run: false,
+            store: StoreOptions::default(),
+            seen_index: None,
+            write_manifest: false,
+            label: None,
+            idle_timeout: None,
+        }
+    }
+
+    fn example_findscu() -> FindScu {
+        FindScu {
+            options: None,
+            cache: FindScuCache::new(None, None, NonZeroUsize::new(1).unwrap()),
+            limiter: FindScuLimiter::new(None),
+        }
+    }
+
+    fn example_storage(root: &camino::Utf8Path) -> Arc<dyn DicomStorage> {
+        Arc::new(FilesystemStorage::new(
+            root.to_path_buf(),
+            Compression::None,
+            FileModes::default(),
+        ))
+    }
+
+    /// [AssociationEvent::Start] of an association from `aec`, whose files are stored as `pacs_name`.
+    fn start_event(
+        ulid: Ulid,
+        aec: &'static str,
+        pacs_name: &'static str,
+        storage: &Arc<dyn DicomStorage>,
+    ) -> AssociationEvent {
+        AssociationEvent::Start {
+            ulid,
+            aec: ClientAETitle::from_static(aec),
+            aet: OurAETitle::from_static("ChRIS"),
+            pacs_name: ClientAETitle::from_static(pacs_name),
+            pacs_address: None,
+            peer_address: None,
+            storage: Arc::clone(storage),
+        }
+    }
+
+    fn instance_event(ulid: Ulid, dcm: DefaultDicomObject) -> AssociationEvent {
+        AssociationEvent::DicomInstance {
+            ulid,
+            dcm,
+            storage: StoredData::Encode,
+            context: opentelemetry::Context::new(),
+        }
+    }
+
+    fn finish_event(ulid: Ulid, ok: bool) -> AssociationEvent {
+        AssociationEvent::Finish {
+            ulid,
+            ok,
+            permit: None,
+        }
+    }
+
+    /// Call [match_event] for each of the `events` with the example settings,
+    /// returning all the messages.
+    fn match_events(
+        events: impl IntoIterator<Item = AssociationEvent>,
+        inflight_associations: &mut HashMap<Ulid, Association>,
+    ) -> Vec<(Ulid, SeriesKeySet, PendingRegistration)> {
+        let options = example_handler_options();
+        let mut findscu = example_findscu();
+        let mut idle_finished = HashSet::new();
+        let mut messages = Vec::new();
+        for event in events {
+            let result = match_event(
+                event,
+                inflight_associations,
+                &mut idle_finished,
+                &options,
+                &mut findscu,
+            );
+            messages.extend(result.unwrap());
+        }
+        messages
+    }
+
+    #[tokio::test]
+    async fn test_aborted_association_is_finished() {
+        let root = temp_dir();
+        let storage = example_storage(&root);
+        let ulid = Ulid::new();
+        let events = [
+            start_event(ulid, "ORTHANC", "ORTHANC", &storage),
+            instance_event(ulid, example_dcm("Chest")),
+            finish_event(ulid, false),
+        ];
+        let mut inflight_associations = HashMap::new();
+        let messages = match_events(events, &mut inflight_associations);
+        assert!(inflight_associations.is_empty());
+        assert!(matches!(
+            messages.last(),
+            Some((_, _, PendingRegistration::End(_)))
+        ));
+        let mut paths = Vec::new();
+        for (_, _, message) in messages {
+            if let PendingRegistration::Task(task, _) = message {
+                paths.push(task.await.unwrap().unwrap().unwrap().path);
+            }
+        }
+        assert_eq!(paths.len(), 3);
+        assert!(paths[0].ends_with("/InstanceNumber-1.2.3.4.5.dcm"));
+        assert!(paths
+            .iter()
+            .any(|path| path.ends_with("/OxidicomAttemptedPushCount=1")));
+    }
+
+    #[tokio::test]
+    async fn test_pacs_name_override() {
+        let root = temp_dir();
+        let storage = example_storage(&root);
+        let ulid = Ulid::new();
+        let events = [
+            start_event(ulid, "CT01", "FriendlyCT", &storage),
+            instance_event(ulid, example_dcm("Chest")),
+            finish_event(ulid, true),
+        ];
+        let messages = match_events(events, &mut HashMap::new());
+        assert!(messages
+            .iter()
+            .all(|(_, series, _)| series.pacs_name.as_str() == "FriendlyCT"));
+        for (_, _, message) in messages {
+            if let PendingRegistration::Task(task, _) = message {
+                let path = task.await.unwrap().unwrap().unwrap().path;
+                assert!(path.contains("SERVICES/PACS/FriendlyCT/"));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle_association_is_finished() {
+        let root = temp_dir();
+        let storage = example_storage(&root);
+        let (tx_events, rx_events) = tokio::sync::mpsc::channel(1);
+        let (tx_messages, mut rx_messages) = tokio::sync::mpsc::unbounded_channel();
+        let options = HandlerOptions {
+            idle_timeout: Some(Duration::from_millis(50)),
+            ..example_handler_options()
+        };
+        let state_loop = tokio::spawn(association_series_state_loop(
+            rx_events,
+            tx_messages,
+            options,
+            example_findscu(),
+        ));
+        let ulid = Ulid::new();
+        tx_events
+            .send(start_event(ulid, "ORTHANC", "ORTHANC", &storage))
+            .await
+            .unwrap();
+        tx_events
+            .send(instance_event(ulid, example_dcm("Chest")))
+            .await
+            .unwrap();
+        let mut tasks = 0;
+        loop {
+            let message = tokio::time::timeout(Duration::from_secs(5), rx_messages.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(message.0, ulid);
+            match message.2 {
+                PendingRegistration::Task(task, _) => {
+                    task.await.unwrap().unwrap();
+                    tasks += 1;
+                }
+                PendingRegistration::End(_) => break,
+            }
+        }
+        // the DICOM instance, NumberOfSeriesRelatedInstances and OxidicomAttemptedPushCount
+        assert_eq!(tasks, 3);
+        // the listener thread was only slow, its late events are ignored
+        tx_events
+            .send(instance_event(ulid, example_dcm("Chest")))
+            .await
+            .unwrap();
+        tx_events.send(finish_event(ulid, true)).await.unwrap();
+        drop(tx_events);
+        let stats = state_loop.await.unwrap().unwrap().unwrap();
+        assert!(rx_messages.recv().await.is_none());
+        assert_eq!(
+            (stats.associations, stats.instances, stats.series),
+            (1, 1, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_and_unknown_association() {
+        let storage = example_storage("/nonexistent".into());
+        let (known, unknown) = (Ulid::new(), Ulid::new());
+        let unreadable = AssociationEvent::Unreadable {
+            ulid: unknown,
+            sop_instance_uid: "1.2.3.4.5".to_string(),
+            data: StoredData::Raw(Vec::new()),
+        };
+        let events = [
+            start_event(known, "ORTHANC", "ORTHANC", &storage),
+            start_event(known, "ORTHANC", "ORTHANC", &storage),
+            instance_event(unknown, example_dcm("Chest")),
+            unreadable,
+            finish_event(unknown, true),
+            finish_event(known, true),
+        ];
+        let (tx_events, rx_events) = tokio::sync::mpsc::channel(events.len());
+        let (tx_messages, mut rx_messages) = tokio::sync::mpsc::unbounded_channel();
+        for event in events {
+            tx_events.send(event).await.unwrap();
+        }
+        drop(tx_events);
+        let result = association_series_state_loop(
+            rx_events,
+            tx_messages,
+            example_handler_options(),
+            example_findscu(),
+        )
+        .await
+        .unwrap();
+        assert!(result.is_ok());
+        assert!(rx_messages.recv().await.is_none());
+    }
+
+    #[rstest]
+    #[case(OnExisting::Overwrite, Ok(true))]
+    #[case(OnExisting::Skip, Ok(false))]
+    #[case(OnExisting::Error, Err(()))]
+    fn test_write_unless_existing(
+        #[case] on_existing: OnExisting,
+        #[case] expected: Result<bool, ()>,
+    ) {
+        let root = temp_dir();
+        let storage = FilesystemStorage::new(root.clone(), Compression::None, FileModes::default());
+        let (pacs_file, _) =
+            PacsFileRegistration::new("ORTHANC".into(), example_dcm("Chest"), &example_options())
+                .unwrap();
+        let first = write_unless_existing(&storage, &pacs_file, on_existing).unwrap();
+        assert!(first.is_some());
+        storage
+            .write_file(&pacs_file.request.path, b"existing")
+            .unwrap();
+        let actual = write_unless_existing(&storage, &pacs_file, on_existing)
+            .map(|location| location.is_some())
+            .map_err(|e| assert!(matches!(e, DicomStorageError::AlreadyExists(_))));
+        assert_eq!(actual, expected);
+        let overwritten = fs_err::read(root.join(&pacs_file.request.path)).unwrap() != b"existing";
+        assert_eq!(overwritten, expected == Ok(true));
+    }
+
+    #[rstest]
+    #[case(Compression::None)]
+    #[case(Compression::Gzip)]
+    fn test_verify_written(#[case] compression: Compression) {
+        let root = temp_dir();
+        let storage = FilesystemStorage::new(root.clone(), compression, FileModes::default());
+        let (pacs_file, _) =
+            PacsFileRegistration::new("ORTHANC".into(), example_dcm("Chest"), &example_options())
+                .unwrap();
+        storage.write_dicom(&pacs_file).unwrap();
+        verify_written(&storage, &pacs_file).unwrap();
+
+        let (mut other, _) =
+            PacsFileRegistration::new("ORTHANC".into(), example_dcm("Head"), &example_options())
+                .unwrap();
+        other.obj.put(dicom::core::DataElement::new(
+            tags::SOP_INSTANCE_UID,
+            dicom::core::VR::UI,
+            dicom::core::PrimitiveValue::from("1.2.3.4.99"),
+        ));
+        other.request.path = pacs_file.request.path.clone();
+        storage.write_dicom(&other).unwrap();
+        let actual = verify_written(&storage, &pacs_file);
+        assert!(
+            matches!(actual, Err(DicomStorageError::VerifyMismatch { actual, .. }) if actual == "1.2.3.4.99")
+        );
+    }
+
+    #[rstest]
+    #[case("1.2.3.4.5", "1.2.3.4.5")]
+    #[case("", "SOPInstanceUID")]
+    #[case(".", "SOPInstanceUID")]
+    #[case("..", "SOPInstanceUID")]
+    #[case("../..", ".._..")]
+    fn test_sanitize_component(#[case] value: &str, #[case] expected: &str) {
+        assert_eq!(sanitize_component(value, "SOPInstanceUID"), expected)
+    }
+
+    /// Storage which fails to write the DICOM instance with `SOPInstanceUID=1.2.3.4.6`.
+    struct FailingStorage(FilesystemStorage);
+
+    impl DicomStorage for FailingStorage {
+        fn write_dicom(
+            &self,
+            pacs_file: &PacsFileRegistration,
+        ) -> Result<String, crate::error::DicomStorageError> {
+            if pacs_file.request.path.contains("1.2.3.4.6") {
+                Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied).into())
+            } else {
+                self.0.write_dicom(pacs_file)
+            }
+        }
+
+        fn write_file(
+            &self,
+            path: &str,
+            data: &[u8],
+        ) -> Result<String, crate::error::DicomStorageError> {
+            self.0.write_file(path, data)
+        }
+
+        fn write_spooled(
+            &self,
+            path: &str,
+            spooled: &camino::Utf8Path,
+        ) -> Result<String, crate::error::DicomStorageError> {
+            self.0.write_spooled(path, spooled)
+        }
+
+        fn quarantine(&self, path: &str) -> Result<String, crate::error::DicomStorageError> {
+            self.0.quarantine(path)
+        }
+
+        fn exists(&self, path: &str) -> Result<bool, crate::error::DicomStorageError> {
+            self.0.exists(path)
+        }
+
+        fn read_header(
+            &self,
+            path: &str,
+        ) -> Result<DefaultDicomObject, crate::error::DicomStorageError> {
+            self.0.read_header(path)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_write_failure_does_not_prevent_finishing_series() {
+        use crate::registration_synchronizer::registration_synchronizer;
+        use dicom::core::{DataElement, VR};
+
+        let root = temp_dir();
+        let storage: Arc<dyn DicomStorage> = Arc::new(FailingStorage(FilesystemStorage::new(
+            root.clone(),
+            Compression::None,
+            FileModes::default(),
+        )));
+        let mut failing_dcm = example_dcm("Chest");
+        failing_dcm.put(DataElement::new(
+            tags::SOP_INSTANCE_UID,
+            VR::UI,
+            "1.2.3.4.6",
+        ));
+        let ulid = Ulid::new();
+        let events = [
+            start_event(ulid, "ORTHANC", "ORTHANC", &storage),
+            instance_event(ulid, example_dcm("Chest")),
+            instance_event(ulid, failing_dcm),
+            finish_event(ulid, true),
+        ];
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        for message in match_events(events, &mut HashMap::new()) {
+            tx.send(message).unwrap();
+        }
+        drop(tx);
+        let (tx_register, mut rx_register) = tokio::sync::mpsc::unbounded_channel();
+        registration_synchronizer(rx, tx_register).await.unwrap();
+        let mut registered = Vec::new();
+        let mut ends = 0;
+        while let Some(message) = rx_register.recv().await {
+            match message {
+                Some(file) => registered.push(file.request.path),
+                None => ends += 1,
+            }
+        }
+        assert_eq!(ends, 1);
+        assert_eq!(registered.len(), 3);
+        assert!(registered.iter().any(|p| p.ends_with("-1.2.3.4.5.dcm")));
+        assert!(!registered.iter().any(|p| p.ends_with("-1.2.3.4.6.dcm")));
+        assert!(registered
+            .iter()
+            .any(|p| p.ends_with("/OxidicomAttemptedPushCount=2")));
+    }
+}
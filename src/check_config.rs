@@ -0,0 +1,76 @@
+//! Validation of configuration without starting the server.
+use crate::get_config;
+use crate::s3::S3Storage;
+use crate::settings::OxidicomEnvOptions;
+use anyhow::Context;
+use camino::Utf8Path;
+use sqlx::postgres::PgPoolOptions;
+
+/// Check the configuration from environment variables, printing a report to stdout.
+///
+/// Checks that the configuration can be parsed, PACS addresses can be resolved,
+/// the database can be connected to, and the storage of every listener is writable.
+/// No TCP ports are bound. Returns whether all checks passed.
+pub async fn check_config_from_env() -> bool {
+    let options: OxidicomEnvOptions = match get_config().extract() {
+        Ok(options) => {
+            report("configuration", &Ok(()));
+            options
+        }
+        Err(e) => {
+            report("configuration", &Err(e.into()));
+            return false;
+        }
+    };
+    let mut results = Vec::new();
+    for (aec, address) in &options.pacs_address {
+        let result = tokio::net::lookup_host(address)
+            .await
+            .map(|_| ())
+            .with_context(|| format!("cannot resolve {address}"));
+        results.push((format!("OXIDICOM_PACS_ADDRESS for {aec}"), result));
+    }
+    results.push(("database".to_string(), check_database(&options).await));
+    if let Some(s3) = options.s3 {
+        let result = S3Storage::new(s3, options.compress).map(|_| ());
+        results.push(("S3 configuration".to_string(), result));
+    } else {
+        let files_roots = std::iter::once(&options.files_root)
+            .chain(options.listeners.iter().map(|l| &l.files_root))
+            .chain(options.called_aet_files_root.values());
+        for files_root in files_roots {
+            let result = check_writable(files_root);
+            results.push((format!("writable {files_root}"), result));
+        }
+    }
+    for (name, result) in &results {
+        report(name, result);
+    }
+    results.iter().all(|(_, result)| result.is_ok())
+}
+
+fn report(name: &str, result: &anyhow::Result<()>) {
+    match result {
+        Ok(()) => println!("ok      {name}"),
+        Err(e) => println!("FAILED  {name}: {e:#}"),
+    }
+}
+
+async fn check_database(options: &OxidicomEnvOptions) -> anyhow::Result<()> {
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&options.db.connection)
+        .await?;
+    sqlx::query("SELECT 1").execute(&pool).await?;
+    pool.close().await;
+    Ok(())
+}
+
+/// Check that a file can be created in `dir`, creating `dir` if needed.
+fn check_writable(dir: &Utf8Path) -> anyhow::Result<()> {
+    fs_err::create_dir_all(dir)?;
+    let path = dir.join(format!(".oxidicom-check-{}", ulid::Ulid::new()));
+    fs_err::write(&path, b"")?;
+    fs_err::remove_file(&path)?;
+    Ok(())
+}
@@ -73,6 +73,18 @@ impl CubePostgresClient {
         }
     }
 
+    /// Get the files which are not registered in CUBE's database.
+    pub async fn unregistered<'a>(
+        &self,
+        files: &'a [PacsFileRegistrationRequest],
+    ) -> Result<Vec<&'a PacsFileRegistrationRequest>, sqlx::Error> {
+        let mut transaction = self.pool.begin().await?;
+        let currently_registered = query_for_existing(&mut transaction, files).await?;
+        let (unregistered_files, _) =
+            separate_existing(files, &currently_registered, |f| f.path.as_str());
+        Ok(unregistered_files)
+    }
+
     /// Get the current time in the local timezone.
     fn get_now(&self) -> OffsetDateTime {
         let now = OffsetDateTime::now_utc();
@@ -212,9 +224,9 @@ async fn warn_and_remove_already_registered<'a>(
 ///
 /// - elements of `x` not found in `y`
 /// - elements of `x` found in `y`
-fn separate_existing<'a, 'b, T, S: AsRef<str>, F>(
+fn separate_existing<'a, T, S: AsRef<str>, F>(
     x: &'a [T],
-    y: &'b [S],
+    y: &[S],
     key_fn: F,
 ) -> (Vec<&'a T>, Vec<&'a str>)
 where
@@ -223,7 +235,7 @@ where
     let existing_items: Vec<&str> = y.iter().map(|s| s.as_ref()).collect();
     let already_registered: Vec<&str> = x
         .iter()
-        .map(|item| key_fn(item))
+        .map(&key_fn)
         .filter(|item| existing_items.contains(item))
         .collect();
     let remaining_items = x
@@ -416,7 +428,7 @@ mod tests {
     fn test_split_existing() {
         let x = ["a", "b", "c", "d", "e"];
         let y = ["b", "d", "e", "f", "g"];
-        let union = vec!["a", "c"];
+        let union = ["a", "c"];
         let only_in_y = vec!["b", "d", "e"];
         let expected = (union.iter().collect(), only_in_y);
         let actual = separate_existing(&x, &y, |s| s);
@@ -507,7 +519,7 @@ mod tests {
         let root = std::env::var("OXIDICOM_FILES_ROOT")
             .map(PathBuf::from)
             .expect("The environment variable OXIDICOM_FILES_ROOT must be set.");
-        futures::stream::iter(requests.into_iter())
+        futures::stream::iter(requests)
             .map(|req| root.join(&req.path))
             .map(Ok)
             .try_for_each_concurrent(4, |p| async move {
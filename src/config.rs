@@ -1,13 +1,70 @@
-use figment::providers::Env;
+use figment::providers::{Env, Format, Json, Toml, Yaml};
 use figment::Figment;
+use std::path::Path;
 use std::sync::OnceLock;
 
 static CONFIG: OnceLock<Figment> = OnceLock::new();
 
+/// Environment variable of the path of a JSON, TOML or YAML file to read configuration from.
+const CONFIG_FILE_ENV: &str = "OXIDICOM_CONFIG_FILE";
+
 pub fn get_config() -> &'static Figment {
-    CONFIG.get_or_init(|| {
+    CONFIG.get_or_init(|| config_with_file(std::env::var_os(CONFIG_FILE_ENV)))
+}
+
+/// Configuration from environment variables, which take precedence over the values
+/// from the file at `config_file` (if given).
+///
+/// The format of the file is TOML if its extension is `.toml`, YAML if it is `.yaml` or `.yml`,
+/// otherwise JSON.
+fn config_with_file<P: AsRef<Path>>(config_file: Option<P>) -> Figment {
+    let figment = if let Some(path) = config_file {
+        let path = path.as_ref();
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => Figment::from(Toml::file_exact(path)),
+            Some("yaml" | "yml") => Figment::from(Yaml::file_exact(path)),
+            _ => Figment::from(Json::file_exact(path)),
+        }
+    } else {
         Figment::new()
-            .merge(Env::prefixed("OXIDICOM_").split("_"))
-            .merge(Env::prefixed("OXIDICOM_"))
-    })
+    };
+    figment
+        .merge(Env::prefixed("OXIDICOM_").split("_"))
+        .merge(Env::prefixed("OXIDICOM_"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use figment::Jail;
+    use rstest::*;
+
+    #[rstest]
+    #[case(
+        "oxidicom.json",
+        r#"{"db": {"connection": "postgres://file"}, "listener_port": 1111}"#
+    )]
+    #[case(
+        "oxidicom.toml",
+        "listener_port = 1111\n[db]\nconnection = \"postgres://file\"\n"
+    )]
+    #[case(
+        "oxidicom.yaml",
+        "db:\n  connection: postgres://file\nlistener_port: 1111\n"
+    )]
+    fn test_config_file_under_env(#[case] file_name: &str, #[case] contents: &str) {
+        Jail::expect_with(|jail| {
+            jail.create_file(file_name, contents)?;
+            jail.set_env("OXIDICOM_LISTENER_PORT", "2222");
+            let config = config_with_file(Some(file_name));
+            let connection: String = config.extract_inner("db.connection")?;
+            assert_eq!(connection, "postgres://file");
+            let port: u16 = config.extract_inner("listener_port")?;
+            assert_eq!(port, 2222);
+
+            let missing = config_with_file(Some("missing.json"));
+            assert!(missing.extract_inner::<String>("db.connection").is_err());
+            Ok(())
+        });
+    }
 }
@@ -2,8 +2,10 @@ use crate::transfer::ABSTRACT_SYNTAXES;
 use aliri_braid::braid;
 use dicom::dictionary_std::uids;
 use dicom::transfer_syntax::TransferSyntaxRegistry;
-use dicom::ul::association::server::AcceptAny;
+use dicom::ul::association::server::{AcceptAny, AcceptCalledAeTitle, AccessControl};
+use dicom::ul::pdu::{AssociationRJServiceUserReason, UserIdentity};
 use dicom::ul::ServerAssociationOptions;
+use std::cell::RefCell;
 
 /// Our AE title.
 #[braid(serde)]
@@ -29,13 +31,63 @@ pub struct DicomRsSettings {
     pub promiscuous: bool,
 }
 
-impl<'a> Into<ServerAssociationOptions<'a, AcceptAny>> for DicomRsSettings {
-    fn into(self) -> ServerAssociationOptions<'a, AcceptAny> {
+/// Access control policy for incoming associations.
+#[derive(Debug, Copy, Clone)]
+pub enum CalledAeTitlePolicy {
+    /// Accept any called AE title.
+    AcceptAny,
+    /// Only accept associations which call us by our AE title.
+    AcceptCalledAeTitle,
+}
+
+thread_local! {
+    /// Called AE title of the association being established on this thread.
+    static CALLED_AE_TITLE: RefCell<Option<OurAETitle>> = const { RefCell::new(None) };
+}
+
+/// Get the AE title which the SCU called us by during the last association established on this thread.
+///
+/// dicom-rs does not expose the called AE title of a [dicom::ul::association::ServerAssociation],
+/// so it is remembered by [CalledAeTitlePolicy::check_access], which is called on the same thread
+/// during [ServerAssociationOptions::establish].
+pub(crate) fn take_called_ae_title() -> Option<OurAETitle> {
+    CALLED_AE_TITLE.with(|called| called.borrow_mut().take())
+}
+
+impl AccessControl for CalledAeTitlePolicy {
+    fn check_access(
+        &self,
+        this_ae_title: &str,
+        calling_ae_title: &str,
+        called_ae_title: &str,
+        user_identity: Option<&UserIdentity>,
+    ) -> Result<(), AssociationRJServiceUserReason> {
+        let trimmed = called_ae_title.trim_matches([' ', '\0']);
+        CALLED_AE_TITLE.with(|called| *called.borrow_mut() = Some(OurAETitle::from(trimmed)));
+        match self {
+            Self::AcceptAny => AcceptAny.check_access(
+                this_ae_title,
+                calling_ae_title,
+                called_ae_title,
+                user_identity,
+            ),
+            Self::AcceptCalledAeTitle => AcceptCalledAeTitle.check_access(
+                this_ae_title,
+                calling_ae_title,
+                called_ae_title,
+                user_identity,
+            ),
+        }
+    }
+}
+
+impl<'a> From<DicomRsSettings> for ServerAssociationOptions<'a, AcceptAny> {
+    fn from(settings: DicomRsSettings) -> Self {
         let mut options = dicom::ul::association::ServerAssociationOptions::new()
             .accept_any()
-            .ae_title(self.aet.to_string())
-            .strict(self.strict);
-        if self.uncompressed_only {
+            .ae_title(settings.aet.to_string())
+            .strict(settings.strict);
+        if settings.uncompressed_only {
             options = options
                 .with_transfer_syntax(uids::IMPLICIT_VR_LITTLE_ENDIAN)
                 .with_transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN);
@@ -49,7 +101,7 @@ impl<'a> Into<ServerAssociationOptions<'a, AcceptAny>> for DicomRsSettings {
         for uid in ABSTRACT_SYNTAXES {
             options = options.with_abstract_syntax(*uid);
         }
-        options.promiscuous(self.promiscuous)
+        options.promiscuous(settings.promiscuous)
     }
 }
 
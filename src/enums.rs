@@ -1,11 +1,17 @@
 use dicom::object::DefaultDicomObject;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::sync::OwnedSemaphorePermit;
 use tokio::task::JoinHandle;
 use ulid::Ulid;
 
 use crate::dicomrs_settings::{ClientAETitle, OurAETitle};
-use crate::pacs_file::PacsFileRegistrationRequest;
+use crate::manifest::PendingManifest;
+use crate::pacs_file::{PacsFileRegistrationRequest, StoredData};
+use crate::storage::DicomStorage;
 
 /// Events which occur during an association.
+#[allow(clippy::large_enum_variant)] // most events are DicomInstance
 pub(crate) enum AssociationEvent {
     /// Association established successfully and client AE title is known.
     Start {
@@ -15,8 +21,14 @@ pub(crate) enum AssociationEvent {
         aec: ClientAETitle,
         /// Our AE title
         aet: OurAETitle,
+        /// `pacs_name` of the received files, usually the same as `aec`
+        pacs_name: ClientAETitle,
         /// Address of the client sending us DICOMs
         pacs_address: Option<String>,
+        /// Address the association was made from, if known
+        peer_address: Option<SocketAddr>,
+        /// Storage of the listener which accepted the association
+        storage: Arc<dyn DicomStorage>,
     },
     /// Received a DICOM file.
     DicomInstance {
@@ -24,14 +36,29 @@ pub(crate) enum AssociationEvent {
         ulid: Ulid,
         /// DICOM data
         dcm: DefaultDicomObject,
+        /// How the DICOM data should be written to storage
+        storage: StoredData,
+        /// OpenTelemetry context of the association, the parent of the span for storing the instance
+        context: opentelemetry::Context,
+    },
+    /// Received a DICOM instance which could not be read, see `OXIDICOM_UNREADABLE_INSTANCES=save`.
+    Unreadable {
+        /// ULID of the association
+        ulid: Ulid,
+        /// `AffectedSOPInstanceUID` of the C-STORE request
+        sop_instance_uid: String,
+        /// The data as it was received, in memory or spooled to disk
+        data: StoredData,
     },
     /// No more DICOM files will be received for this association.
     Finish {
         /// ULID of the association
         ulid: Ulid,
-        /// Whether there was an error with the association
-        #[allow(unused)]
+        /// Whether there was an error with the association, e.g. it was aborted.
+        /// Either way, the series which were received are finished.
         ok: bool,
+        /// Permit limiting the number of associations in flight, see `OXIDICOM_MAX_INFLIGHT_ASSOCIATIONS`
+        permit: Option<OwnedSemaphorePermit>,
     },
 }
 
@@ -39,10 +66,24 @@ pub(crate) enum AssociationEvent {
 /// to [crate::registration_synchronizer::registration_synchronizer].
 pub(crate) enum PendingRegistration {
     /// A task which, if successful, produces a [PacsFileRegistrationRequest] which should
-    /// be added to a batch in preparation for registration to the database.
+    /// be added to a batch in preparation for registration to the database. It produces
+    /// [None] if the file was not stored on purpose, e.g. it is in `OXIDICOM_SEEN_INDEX`.
     ///
     /// Error handling should be done by the sender, so the [Err] type is `()`.
-    Task(JoinHandle<Result<PacsFileRegistrationRequest, ()>>),
+    /// The file is in the given storage.
+    Task(StorageTask, Arc<dyn DicomStorage>),
     /// Indicates that no other tasks shall be sent for a given series.
-    End,
+    ///
+    /// The manifest, if any, is written once the tasks of the series are done.
+    End(Option<PendingManifest>),
+}
+
+/// A task which stores a DICOM instance, see [PendingRegistration::Task].
+pub(crate) type StorageTask = JoinHandle<Result<Option<PacsFileRegistrationRequest>, ()>>;
+
+/// A file which was written to storage and should be registered to _CUBE_.
+pub(crate) struct StoredPacsFile {
+    pub request: PacsFileRegistrationRequest,
+    /// Where the file was written to
+    pub storage: Arc<dyn DicomStorage>,
 }
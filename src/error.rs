@@ -10,21 +10,64 @@ pub enum DicomStorageError {
     IO(#[from] std::io::Error),
 
     #[error(transparent)]
-    Write(#[from] dicom::object::WriteError),
+    Write(Box<dicom::object::WriteError>),
+
+    #[error(transparent)]
+    Meta(Box<dicom::object::meta::Error>),
+
+    #[error(transparent)]
+    Http(#[from] reqwest::Error),
+
+    #[error("S3 responded with {status}: {message}")]
+    S3 {
+        status: reqwest::StatusCode,
+        message: String,
+    },
+
+    #[error("File already exists: {0}")]
+    AlreadyExists(String),
+
+    #[error(transparent)]
+    Read(Box<dicom::object::ReadError>),
+
+    #[error("Written file {path} has SOPInstanceUID={actual}, expected {expected}")]
+    VerifyMismatch {
+        path: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl From<dicom::object::WriteError> for DicomStorageError {
+    fn from(e: dicom::object::WriteError) -> Self {
+        Self::Write(Box::new(e))
+    }
+}
+
+impl From<dicom::object::meta::Error> for DicomStorageError {
+    fn from(e: dicom::object::meta::Error) -> Self {
+        Self::Meta(Box::new(e))
+    }
+}
+
+impl From<dicom::object::ReadError> for DicomStorageError {
+    fn from(e: dicom::object::ReadError) -> Self {
+        Self::Read(Box::new(e))
+    }
 }
 
 #[derive(thiserror::Error, Debug)]
 pub enum RequiredTagError {
     #[error("DICOM file does not have the required tag: {}", name_of(.0))]
     Missing(Tag),
-    #[error("Illegal value for tag {}={:?}", name_of(&.0.tag), .0.value)]
+    #[error("Illegal value for tag {0}")]
     Bad(BadTag),
 }
 
 #[derive(thiserror::Error, Debug)]
 #[error("{error}")]
 pub struct DicomRequiredTagError {
-    pub obj: DefaultDicomObject,
+    pub obj: Box<DefaultDicomObject>,
     pub error: RequiredTagError,
 }
 
@@ -39,4 +82,38 @@ pub(crate) fn name_of(tag: &Tag) -> &'static str {
 /// Error happened during an iteration of a loop.
 #[derive(Debug, thiserror::Error)]
 #[error("{0}")]
-pub(crate) struct HandleLoopError(pub &'static str);
+pub struct HandleLoopError(pub &'static str);
+
+/// Error from [crate::run_everything_from_env].
+///
+/// Errors which happen before any DICOM is received are distinguished from [RunError::Processing],
+/// which means that some of the received DICOM instances might not have been stored or registered.
+#[derive(thiserror::Error, Debug)]
+pub enum RunError {
+    #[error("Invalid configuration")]
+    Config(#[from] Box<figment::Error>),
+
+    #[error("Could not connect to the database")]
+    Database(#[source] sqlx::Error),
+
+    #[error("Could not open OXIDICOM_SEEN_INDEX")]
+    SeenIndex(#[source] sqlx::Error),
+
+    #[error("Invalid object storage configuration: {0:#}")]
+    S3(anyhow::Error),
+
+    #[error("Could not create spool directory")]
+    SpoolDir(#[source] std::io::Error),
+
+    #[error("Could not listen on {address}")]
+    Bind {
+        address: std::net::SocketAddr,
+        source: std::io::Error,
+    },
+
+    #[error(transparent)]
+    Processing(#[from] HandleLoopError),
+
+    #[error("A DICOM listener panicked")]
+    Listener(#[from] tokio::task::JoinError),
+}
@@ -1,11 +1,11 @@
-//! DICOM FIND to get NumberOfSeriesRelatedInstances.
+//! DICOM FIND to get NumberOfSeriesRelatedInstances (and optionally other series attributes).
 //!
 //! Mostly based on
 //! https://github.com/Enet4/dicom-rs/tree/7c0e5ab895e2f57c432cece41077f13abd4d7f71/findscu
 
 use crate::dicomrs_settings::{ClientAETitle, OurAETitle};
 use anyhow::{bail, Context};
-use dicom::core::{DataElement, PrimitiveValue, VR};
+use dicom::core::{DataElement, PrimitiveValue, Tag, VR};
 use dicom::dicom_value;
 use dicom::dictionary_std::{tags, uids};
 use dicom::encoding::TransferSyntaxIndex;
@@ -17,6 +17,8 @@ use opentelemetry::trace::{Status, TraceContextExt, Tracer};
 use opentelemetry::{global, KeyValue};
 use std::borrow::Cow;
 use std::io::Read;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
 use ulid::Ulid;
 
 pub(crate) struct FindScuParameters {
@@ -26,48 +28,141 @@ pub(crate) struct FindScuParameters {
     pub(crate) aet: OurAETitle,
     pub(crate) study_instance_uid: String,
     pub(crate) series_instance_uid: String,
+    /// Whether to also ask for `Modality` and `SeriesDescription`
+    pub(crate) extra_keys: bool,
+    pub(crate) options: FindScuOptions,
+}
+
+/// Settings for C-FIND requests to PACS servers.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct FindScuOptions {
+    /// Maximum time to wait for the PACS to send or receive data
+    pub timeout: Option<Duration>,
+    /// Number of times to try again after a failed attempt
+    pub retries: usize,
+    /// Time to wait before each C-FIND
+    pub delay: Option<Duration>,
+    /// Maximum random time to wait in addition to [FindScuOptions::delay]
+    pub jitter: Option<Duration>,
+}
+
+impl FindScuOptions {
+    /// Maximum time for all attempts of a C-FIND to complete.
+    pub(crate) fn deadline(&self) -> Option<Duration> {
+        self.timeout
+            .map(|timeout| timeout.saturating_mul(self.retries as u32 + 1))
+    }
+
+    /// Time to wait before a C-FIND, which is [FindScuOptions::delay] plus a random jitter.
+    pub(crate) fn wait_time(&self) -> Duration {
+        let jitter = self
+            .jitter
+            .map(|jitter| jitter.mul_f64(fastrand::f64()))
+            .unwrap_or_default();
+        self.delay.unwrap_or_default() + jitter
+    }
+}
+
+/// Attributes of a series reported by the PACS.
+#[derive(Debug, Clone)]
+pub(crate) struct SeriesInfo {
+    pub(crate) number_of_series_related_instances: usize,
+    /// Only requested if [FindScuParameters::extra_keys]
+    pub(crate) modality: Option<String>,
+    /// Only requested if [FindScuParameters::extra_keys]
+    pub(crate) series_description: Option<String>,
 }
 
 impl FindScuParameters {
-    pub(crate) fn get_number_of_series_related_instances(&self) -> Result<usize, ()> {
+    /// Get information about the series from the PACS, trying again up to
+    /// [FindScuOptions::retries] times.
+    pub(crate) fn get_series_info(&self) -> Result<SeriesInfo, ()> {
         let tracer = global::tracer(env!("CARGO_PKG_NAME"));
         tracer.in_span("findscu", |cx| {
             cx.span().set_attributes(self.to_otel_attributes());
-            match self.try_get_number_of_series_related_instances(&cx) {
-                Ok(num) => {
-                    cx.span().set_status(Status::Ok);
-                    Ok(num)
-                }
-                Err(err) => {
-                    tracing::error!(
-                        association_ulid = self.ulid.to_string(),
-                        pacs_address = &self.pacs_address,
-                        aec = self.aec.as_str(),
-                        aet = self.aet.as_str(),
-                        StudyInstanceUID = &self.study_instance_uid,
-                        SeriesInstanceUID = &self.series_instance_uid,
-                        message = err.to_string(),
-                    );
-                    cx.span().set_status(Status::Error {
-                        description: Cow::Owned(err.to_string()),
-                    });
-                    Err(())
+            let mut attempt = 0;
+            loop {
+                match self.try_get_series_info(&cx) {
+                    Ok(info) => {
+                        cx.span().set_status(Status::Ok);
+                        return Ok(info);
+                    }
+                    Err(err) if attempt < self.options.retries => {
+                        attempt += 1;
+                        tracing::warn!(
+                            association_ulid = self.ulid.to_string(),
+                            pacs_address = &self.pacs_address,
+                            SeriesInstanceUID = &self.series_instance_uid,
+                            attempt = attempt,
+                            message = err.to_string(),
+                        );
+                        cx.span().add_event(
+                            "retry",
+                            vec![
+                                KeyValue::new("attempt", attempt as i64),
+                                KeyValue::new("error", err.to_string()),
+                            ],
+                        );
+                    }
+                    Err(err) => {
+                        tracing::error!(
+                            association_ulid = self.ulid.to_string(),
+                            pacs_address = &self.pacs_address,
+                            aec = self.aec.as_str(),
+                            aet = self.aet.as_str(),
+                            StudyInstanceUID = &self.study_instance_uid,
+                            SeriesInstanceUID = &self.series_instance_uid,
+                            message = err.to_string(),
+                        );
+                        cx.span().set_status(Status::Error {
+                            description: Cow::Owned(err.to_string()),
+                        });
+                        return Err(());
+                    }
                 }
             }
         })
     }
 
-    fn try_get_number_of_series_related_instances(
-        &self,
-        cx: &opentelemetry::Context,
-    ) -> anyhow::Result<usize> {
+    /// Resolve the address of the PACS, returning the first one which accepts a TCP connection
+    /// within `timeout`. Establishing an association does not time out while connecting,
+    /// so this avoids waiting forever for an unreachable PACS.
+    fn connectable_address(&self, timeout: Duration) -> anyhow::Result<SocketAddr> {
+        let mut last_error = None;
+        let addresses = self
+            .pacs_address
+            .to_socket_addrs()
+            .with_context(|| format!("Could not resolve {}", self.pacs_address))?;
+        for address in addresses {
+            match TcpStream::connect_timeout(&address, timeout) {
+                Ok(_) => return Ok(address),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        match last_error {
+            Some(e) => {
+                Err(e).with_context(|| format!("Could not connect to {}", self.pacs_address))
+            }
+            None => bail!("{} did not resolve to any address", self.pacs_address),
+        }
+    }
+
+    fn try_get_series_info(&self, cx: &opentelemetry::Context) -> anyhow::Result<SeriesInfo> {
         let abstract_syntax = uids::STUDY_ROOT_QUERY_RETRIEVE_INFORMATION_MODEL_FIND;
         let scu_opt = ClientAssociationOptions::new()
             .with_abstract_syntax(abstract_syntax)
             .calling_ae_title(self.aet.as_str())
             .called_ae_title(self.aec.as_str())
             .max_pdu_length(16384);
-        let mut scu = scu_opt.establish_with(&self.pacs_address)?;
+        let mut scu = if let Some(timeout) = self.options.timeout {
+            scu_opt.establish(self.connectable_address(timeout)?)?
+        } else {
+            scu_opt.establish_with(&self.pacs_address)?
+        };
+        scu.inner_stream()
+            .set_read_timeout(self.options.timeout)
+            .and_then(|_| scu.inner_stream().set_write_timeout(self.options.timeout))
+            .context("Could not set timeout")?;
         let pc_selected = scu
             .presentation_contexts()
             .first()
@@ -189,7 +284,7 @@ impl FindScuParameters {
             }
         }
         let _ = scu.release();
-        self.get_number_from(dicoms)
+        self.get_info_from(dicoms)
     }
 
     fn to_dicom_query(&self) -> InMemDicomObject {
@@ -214,29 +309,43 @@ impl FindScuParameters {
             VR::IS,
             PrimitiveValue::Empty,
         ));
+        if self.extra_keys {
+            obj.put(DataElement::new(
+                tags::MODALITY,
+                VR::CS,
+                PrimitiveValue::Empty,
+            ));
+            obj.put(DataElement::new(
+                tags::SERIES_DESCRIPTION,
+                VR::LO,
+                PrimitiveValue::Empty,
+            ));
+        }
         obj
     }
 
-    /// Extract and parse the value for `NumberOfSeriesRelatedInstances` among several DICOM objects.
-    fn get_number_from(
+    /// Find the response for the series which has a valid value for `NumberOfSeriesRelatedInstances`
+    /// among several DICOM objects.
+    fn get_info_from(
         &self,
         dicoms: impl IntoIterator<Item = InMemDicomObject>,
-    ) -> anyhow::Result<usize> {
+    ) -> anyhow::Result<SeriesInfo> {
         dicoms
             .into_iter()
             .filter(|dcm| {
                 dcm.get(tags::SERIES_INSTANCE_UID)
                     .and_then(|ele| ele.string().ok())
                     .map(|uid| uid.replace('\0', ""))
-                    .is_some_and(|uid| uid.trim() == &self.series_instance_uid)
+                    .is_some_and(|uid| uid.trim() == self.series_instance_uid)
             })
             .find_map(|dcm| {
-                dcm.get(tags::NUMBER_OF_SERIES_RELATED_INSTANCES)
+                let number_of_series_related_instances = dcm
+                    .get(tags::NUMBER_OF_SERIES_RELATED_INSTANCES)
                     .and_then(|ele| ele.string().ok())
                     .and_then(|s| {
                         s.trim()
                             .parse()
-                            .map_err(|e| {
+                            .inspect_err(|_| {
                                 tracing::warn!(
                                     error = "Invalid number returned from PACS",
                                     pacs_address = &self.pacs_address,
@@ -245,10 +354,20 @@ impl FindScuParameters {
                                     tag = "NumberOfSeriesRelatedInstances",
                                     value = s
                                 );
-                                e
                             })
                             .ok()
-                    })
+                    })?;
+                let string_of = |tag: Tag| {
+                    dcm.get(tag)
+                        .and_then(|ele| ele.string().ok())
+                        .map(|s| s.trim_end_matches([' ', '\0']).to_string())
+                        .filter(|s| !s.is_empty())
+                };
+                Some(SeriesInfo {
+                    number_of_series_related_instances,
+                    modality: string_of(tags::MODALITY),
+                    series_description: string_of(tags::SERIES_DESCRIPTION),
+                })
             })
             .ok_or_else(|| {
                 anyhow::Error::msg(
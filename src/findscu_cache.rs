@@ -0,0 +1,154 @@
+//! Reuse of C-FIND results for series which are pushed more than once.
+use crate::findscu::SeriesInfo;
+use futures::future::{BoxFuture, Shared};
+use hashlink::LruCache;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+/// Attributes of a series reported by the PACS, if known.
+pub(crate) type PacsSeriesInfo = Shared<BoxFuture<'static, Option<SeriesInfo>>>;
+
+/// A bounded cache of [PacsSeriesInfo] keyed by PACS address and `SeriesInstanceUID`.
+///
+/// C-FINDs still in progress are cached too, so concurrent pushes of a series share one query.
+/// Once the C-FIND is done, its result expires `ttl` after it was started, or `unknown_ttl`
+/// if the PACS could not tell us about the series. When full, the least-recently-used
+/// entry is evicted.
+pub(crate) struct FindScuCache {
+    ttl: Option<Duration>,
+    unknown_ttl: Duration,
+    entries: LruCache<(String, String), (Instant, PacsSeriesInfo)>,
+}
+
+impl FindScuCache {
+    /// Create a cache. If `ttl` is [None], nothing is cached.
+    ///
+    /// `unknown_ttl` is limited to be no longer than `ttl`.
+    pub fn new(
+        ttl: Option<Duration>,
+        unknown_ttl: Option<Duration>,
+        capacity: NonZeroUsize,
+    ) -> Self {
+        let unknown_ttl = unknown_ttl.unwrap_or_default().min(ttl.unwrap_or_default());
+        Self {
+            ttl,
+            unknown_ttl,
+            entries: LruCache::new(capacity.get()),
+        }
+    }
+
+    /// Get the cached C-FIND result for a series, or else create and cache it using `query`.
+    pub fn get_or_insert_with(
+        &mut self,
+        pacs_address: &str,
+        series_instance_uid: &str,
+        query: impl FnOnce() -> PacsSeriesInfo,
+    ) -> PacsSeriesInfo {
+        self.get_or_insert_with_at(Instant::now(), pacs_address, series_instance_uid, query)
+    }
+
+    fn get_or_insert_with_at(
+        &mut self,
+        now: Instant,
+        pacs_address: &str,
+        series_instance_uid: &str,
+        query: impl FnOnce() -> PacsSeriesInfo,
+    ) -> PacsSeriesInfo {
+        let Some(ttl) = self.ttl else {
+            return query();
+        };
+        let key = (pacs_address.to_string(), series_instance_uid.to_string());
+        if let Some((started, info)) = self.entries.get(&key) {
+            let ttl = match info.peek() {
+                Some(None) => self.unknown_ttl,
+                _ => ttl,
+            };
+            if now.duration_since(*started) < ttl {
+                return info.clone();
+            }
+        }
+        let info = query();
+        self.entries.insert(key, (now, info.clone()));
+        info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn resolved(info: Option<SeriesInfo>) -> PacsSeriesInfo {
+        let info = futures::future::ready(info).boxed().shared();
+        info.clone().now_or_never();
+        info
+    }
+
+    fn known(n: usize) -> PacsSeriesInfo {
+        resolved(Some(SeriesInfo {
+            number_of_series_related_instances: n,
+            modality: None,
+            series_description: None,
+        }))
+    }
+
+    fn number_of(info: PacsSeriesInfo) -> Option<usize> {
+        info.peek()
+            .unwrap()
+            .as_ref()
+            .map(|i| i.number_of_series_related_instances)
+    }
+
+    #[test]
+    fn test_findscu_cache_ttl() {
+        let mut cache = FindScuCache::new(
+            Some(Duration::from_secs(60)),
+            Some(Duration::from_secs(5)),
+            NonZeroUsize::new(10).unwrap(),
+        );
+        let t0 = Instant::now();
+        let mut get = |s, pacs, series, info| {
+            let now = t0 + Duration::from_secs(s);
+            number_of(cache.get_or_insert_with_at(now, pacs, series, || info))
+        };
+        assert_eq!(get(0, "pacs:4242", "1.2.3", known(1)), Some(1));
+        assert_eq!(get(0, "pacs:4242", "4.5.6", resolved(None)), None);
+        assert_eq!(get(4, "pacs:4242", "4.5.6", known(2)), None);
+        assert_eq!(get(30, "pacs:4242", "1.2.3", known(3)), Some(1));
+        assert_eq!(get(30, "pacs:4242", "4.5.6", known(4)), Some(4));
+        assert_eq!(get(30, "other:4242", "1.2.3", known(5)), Some(5));
+        assert_eq!(get(61, "pacs:4242", "1.2.3", known(6)), Some(6));
+    }
+
+    #[test]
+    fn test_findscu_cache_evicts_least_recently_used() {
+        let mut cache = FindScuCache::new(
+            Some(Duration::from_secs(60)),
+            None,
+            NonZeroUsize::new(2).unwrap(),
+        );
+        let now = Instant::now();
+        let mut get =
+            |series, info| number_of(cache.get_or_insert_with_at(now, "pacs", series, || info));
+        assert_eq!(get("1", known(1)), Some(1));
+        assert_eq!(get("2", known(2)), Some(2));
+        assert_eq!(get("1", known(10)), Some(1));
+        assert_eq!(get("3", known(3)), Some(3));
+        assert_eq!(get("1", known(100)), Some(1));
+        assert_eq!(get("2", known(200)), Some(200));
+    }
+
+    #[test]
+    fn test_findscu_cache_disabled() {
+        let mut cache = FindScuCache::new(None, None, NonZeroUsize::new(2).unwrap());
+        let now = Instant::now();
+        assert_eq!(
+            number_of(cache.get_or_insert_with_at(now, "pacs", "1", || known(1))),
+            Some(1)
+        );
+        assert_eq!(
+            number_of(cache.get_or_insert_with_at(now, "pacs", "1", || known(2))),
+            Some(2)
+        );
+    }
+}
@@ -0,0 +1,58 @@
+//! Checking for available space in storage.
+use camino::Utf8PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a measurement of available space is reused for.
+const CACHE_DURATION: Duration = Duration::from_secs(5);
+
+/// Checks whether the filesystem of a path has at least a minimum amount of space available.
+///
+/// The most recent measurement is cached for [CACHE_DURATION].
+pub(crate) struct FreeSpaceGuard {
+    path: Utf8PathBuf,
+    min_free_bytes: u64,
+    last: Mutex<Option<(Instant, u64)>>,
+}
+
+impl FreeSpaceGuard {
+    pub fn new(path: Utf8PathBuf, min_free_bytes: u64) -> Self {
+        Self {
+            path,
+            min_free_bytes,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// Returns the available space if it is less than the minimum.
+    ///
+    /// If the available space cannot be determined, it is assumed to be sufficient.
+    pub fn insufficient(&self) -> Option<u64> {
+        self.available_space()
+            .filter(|available| *available < self.min_free_bytes)
+    }
+
+    /// The minimum number of bytes which should be available.
+    pub fn min_free_bytes(&self) -> u64 {
+        self.min_free_bytes
+    }
+
+    fn available_space(&self) -> Option<u64> {
+        let mut last = self.last.lock().unwrap();
+        if let Some((time, available)) = *last {
+            if time.elapsed() < CACHE_DURATION {
+                return Some(available);
+            }
+        }
+        match fs4::available_space(&self.path) {
+            Ok(available) => {
+                *last = Some((Instant::now(), available));
+                Some(available)
+            }
+            Err(e) => {
+                tracing::warn!(path = self.path.as_str(), message = e.to_string());
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! HTTP health check for container orchestration.
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::Router;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Serve `/health` forever. It responds with 200 when `ready` is true, otherwise 503.
+pub(crate) async fn health_server(
+    address: SocketAddr,
+    ready: Arc<AtomicBool>,
+) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/health", get(get_health))
+        .with_state(ready);
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    tracing::info!(health_address = address.to_string(), "Serving health check");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn get_health(State(ready): State<Arc<AtomicBool>>) -> StatusCode {
+    if ready.load(Ordering::Relaxed) {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    }
+}
@@ -4,26 +4,46 @@ mod chrisdb_client;
 // mod custom_metadata;
 mod association_series_state_loop;
 mod batcher;
+mod check_config;
 mod config;
 mod dicomrs_settings;
 mod enums;
 mod error;
 mod findscu;
+mod findscu_cache;
+mod free_space;
+mod health;
 mod listener_tcp_loop;
+mod manifest;
+mod metrics;
 mod pacs_file;
+mod pacs_limiter;
+mod path_template;
 mod patient_age;
 mod private_sop_uids;
+mod redact;
 mod registerer;
 mod registration_synchronizer;
+mod replay;
 mod run_everything;
+mod s3;
 mod sanitize;
 mod scp;
+mod seen_index;
 mod series_key_set;
 mod settings;
+mod spool;
+mod storage;
+#[cfg(test)]
+mod test_util;
 mod thread_pool;
 mod transfer;
+mod transfer_syntax_check;
 
+pub use check_config::check_config_from_env;
 pub use config::get_config;
 pub use dicomrs_settings::DicomRsSettings;
-pub use run_everything::run_everything_from_env;
+pub use error::RunError;
+pub use replay::replay_from_env;
+pub use run_everything::{run_everything_from_env, RunStats};
 pub use series_key_set::OXIDICOM_CUSTOM_PACS_NAME;
@@ -1,85 +1,135 @@
-use crate::dicomrs_settings::{ClientAETitle, DicomRsSettings};
 use crate::enums::AssociationEvent;
-use crate::scp::handle_association;
+use crate::scp::{handle_association, ScpParameters};
 use crate::thread_pool::ThreadPool;
 use opentelemetry::trace::{Status, TraceContextExt, Tracer};
 use opentelemetry::{global, Context, KeyValue};
 use opentelemetry_semantic_conventions as semconv;
-use std::collections::HashMap;
-use std::net::{SocketAddrV4, TcpListener, TcpStream};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::Sender;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Limits on the connections handled by [dicom_listener_tcp_loop].
+pub(crate) struct ConnectionLimits {
+    /// Stop after accepting this many connections.
+    pub finite_connections: Option<usize>,
+    /// Number of threads handling associations.
+    pub n_threads: usize,
+    /// Maximum number of associations which are not finished yet.
+    pub inflight_limit: Option<Arc<Semaphore>>,
+}
 
 /// Listen for incoming DICOM instances on a TCP port.
 ///
 /// Every TCP connection is handled by [handle_association], which transmits DICOM instance file
-/// objects through the given `handler`.
-pub fn dicom_listener_tcp_loop(
-    address: SocketAddrV4,
-    config: DicomRsSettings,
-    finite_connections: Option<usize>,
-    n_threads: usize,
-    max_pdu_length: usize,
-    handler: UnboundedSender<AssociationEvent>,
-    pacs_addresses: HashMap<ClientAETitle, String>,
-) -> anyhow::Result<()> {
+/// objects through the given `handler`. [AssociationEvent::Finish] is sent for every association,
+/// even if its thread panics.
+///
+/// `on_start` is called once the TCP port is bound.
+///
+/// When `shutdown` is set, no more connections are accepted (after the next incoming
+/// connection, which is dropped) and the loop returns once the in-flight associations
+/// are done.
+///
+/// If [ConnectionLimits::inflight_limit] is given, a permit is acquired before accepting each
+/// connection. The permit is sent along with [AssociationEvent::Finish], so no more connections
+/// are accepted while the receiver of `handler` is behind on that many associations.
+///
+/// When `handler` is full, the worker threads block until it has room, so they stop
+/// reading from their associations.
+pub(crate) fn dicom_listener_tcp_loop(
+    address: SocketAddr,
+    params: ScpParameters<'static>,
+    limits: ConnectionLimits,
+    handler: Sender<AssociationEvent>,
+    on_start: impl FnOnce(),
+    shutdown: Arc<AtomicBool>,
+) -> std::io::Result<()> {
+    let ConnectionLimits {
+        finite_connections,
+        n_threads,
+        inflight_limit,
+    } = limits;
     let listener = TcpListener::bind(address)?;
     tracing::info!("listening on: tcp://{}", address);
+    on_start();
     let mut pool = ThreadPool::new(n_threads, "dicom_listener");
-    let ae_title = Arc::new(config.aet.clone());
-    let pacs_addresses = Arc::new(pacs_addresses);
-    let options = Arc::new(config.into());
+    let params = Arc::new(params);
     let handler = Arc::new(handler);
-    let incoming: Box<dyn Iterator<Item = Result<TcpStream, _>>> =
+    let mut incoming: Box<dyn Iterator<Item = Result<TcpStream, _>>> =
         if let Some(n) = finite_connections {
             Box::new(listener.incoming().take(n))
         } else {
             Box::new(listener.incoming())
         };
     let tracer = global::tracer(env!("CARGO_PKG_NAME"));
-    for stream in incoming {
+    loop {
+        let permit = inflight_limit.as_ref().map(|semaphore| {
+            tokio::runtime::Handle::current()
+                .block_on(Arc::clone(semaphore).acquire_owned())
+                .unwrap()
+        });
+        let Some(stream) = incoming.next() else {
+            break;
+        };
+        if shutdown.load(Ordering::Relaxed) {
+            tracing::info!("Shutting down, waiting for in-flight associations to finish.");
+            break;
+        }
         tracer.in_span("association", |cx| match stream {
             Ok(scu_stream) => {
-                let options = Arc::clone(&options);
+                let params = Arc::clone(&params);
                 let handler = Arc::clone(&handler);
-                let ae_title = Arc::clone(&ae_title);
-                let pacs_address = Arc::clone(&pacs_addresses);
                 pool.execute(move || {
                     let ulid = ulid::Ulid::new();
+                    let mut finish = FinishGuard {
+                        ulid,
+                        ok: false,
+                        permit,
+                        handler: Arc::clone(&handler),
+                    };
                     let _context_guard = cx.attach();
                     let context = Context::current();
                     let association_attribute = KeyValue::new("association_ulid", ulid.to_string());
                     context.span().set_attribute(association_attribute);
                     if let Ok(address) = scu_stream.peer_addr() {
+                        // IPv4 clients of a dual-stack listener have IPv4-mapped IPv6 addresses
+                        let ip = address.ip().to_canonical();
+                        let network_type = if ip.is_ipv4() { "ipv4" } else { "ipv6" };
                         let peer_attributes = vec![
-                            KeyValue::new(semconv::trace::CLIENT_ADDRESS, address.ip().to_string()),
+                            KeyValue::new(semconv::trace::CLIENT_ADDRESS, ip.to_string()),
                             KeyValue::new(semconv::trace::CLIENT_PORT, address.port() as i64),
+                            KeyValue::new(semconv::trace::NETWORK_TYPE, network_type),
                         ];
                         context.span().set_attributes(peer_attributes);
                     }
-                    match handle_association(
-                        scu_stream,
-                        &options,
-                        max_pdu_length,
-                        &handler,
-                        ulid,
-                        &ae_title,
-                        &pacs_address,
-                    ) {
-                        Ok(..) => {
-                            handler
-                                .send(AssociationEvent::Finish { ulid, ok: true })
-                                .unwrap();
-                            context.span().set_status(Status::Ok)
+                    finish.ok = match handle_association(scu_stream, &params, &handler, ulid) {
+                        Ok(instances) => {
+                            context
+                                .span()
+                                .set_attribute(KeyValue::new("instances", instances as i64));
+                            context.span().set_status(Status::Ok);
+                            if instances == 0 {
+                                tracing::warn!(
+                                    association_ulid = ulid.to_string(),
+                                    "Association finished without receiving any DICOM instances."
+                                );
+                            } else {
+                                tracing::info!(
+                                    association_ulid = ulid.to_string(),
+                                    instances,
+                                    "Association finished."
+                                );
+                            }
+                            true
                         }
                         Err(e) => {
                             tracing::error!("{:?}", e);
-                            handler
-                                .send(AssociationEvent::Finish { ulid, ok: false })
-                                .unwrap();
-                            context.span().set_status(Status::error(e.to_string()))
+                            context.span().set_status(Status::error(e.to_string()));
+                            false
                         }
-                    }
+                    };
                 });
             }
             Err(e) => cx.span().set_status(Status::error(e.to_string())),
@@ -88,3 +138,28 @@ pub fn dicom_listener_tcp_loop(
     pool.shutdown();
     Ok(())
 }
+
+/// Sends [AssociationEvent::Finish] when dropped, so that the association is finished even if
+/// its thread panics while handling it.
+struct FinishGuard {
+    ulid: ulid::Ulid,
+    ok: bool,
+    permit: Option<OwnedSemaphorePermit>,
+    handler: Arc<Sender<AssociationEvent>>,
+}
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        let event = AssociationEvent::Finish {
+            ulid: self.ulid,
+            ok: self.ok,
+            permit: self.permit.take(),
+        };
+        if self.handler.blocking_send(event).is_err() {
+            tracing::error!(
+                association_ulid = self.ulid.to_string(),
+                "Could not finish association, the receiver is gone."
+            );
+        }
+    }
+}
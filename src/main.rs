@@ -1,14 +1,31 @@
 //! Initialize OpenTelemetry, then call [oxidicom::run_everything_from_env].
+//!
+//! If `OXIDICOM_CHECK_CONFIG` is set, call [oxidicom::check_config_from_env] instead.
+//! If `OXIDICOM_REPLAY` is set, call [oxidicom::replay_from_env] instead.
 
 use oxidicom::get_config;
 
 #[tokio::main(flavor = "multi_thread")]
 async fn main() -> anyhow::Result<()> {
-    init_tracing_subscriber().unwrap();
+    init_tracing_subscriber()?;
+    if get_config()
+        .extract_inner_lossy("check_config")
+        .unwrap_or(false)
+    {
+        let ok = oxidicom::check_config_from_env().await;
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+    if get_config().extract_inner_lossy("replay").unwrap_or(false) {
+        return oxidicom::replay_from_env().await;
+    }
     init_otel_tracing().unwrap();
     let result = oxidicom::run_everything_from_env(None).await;
     opentelemetry::global::shutdown_tracer_provider();
-    result
+    let stats = result?;
+    if stats.errors > 0 {
+        anyhow::bail!("{} files could not be written or registered", stats.errors);
+    }
+    Ok(())
 }
 
 fn init_otel_tracing() -> Result<opentelemetry_sdk::trace::Tracer, opentelemetry::trace::TraceError>
@@ -19,15 +36,32 @@ fn init_otel_tracing() -> Result<opentelemetry_sdk::trace::Tracer, opentelemetry
         .install_batch(opentelemetry_sdk::runtime::Tokio)
 }
 
-fn init_tracing_subscriber() -> Result<(), tracing::dispatcher::SetGlobalDefaultError> {
+/// Format of log messages, configured by `OXIDICOM_LOG_FORMAT`.
+#[derive(serde::Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, for log aggregation
+    Json,
+}
+
+fn init_tracing_subscriber() -> anyhow::Result<()> {
     let level = if get_config().extract_inner_lossy("verbose").unwrap_or(false) {
         tracing::Level::INFO
     } else {
         tracing::Level::WARN
     };
-    tracing::subscriber::set_global_default(
-        tracing_subscriber::FmtSubscriber::builder()
-            .with_max_level(level)
-            .finish(),
-    )
+    let format = match get_config().extract_inner("log_format") {
+        Err(e) if e.missing() => LogFormat::default(),
+        result => result?,
+    };
+    let builder = tracing_subscriber::FmtSubscriber::builder().with_max_level(level);
+    match format {
+        LogFormat::Text => tracing::subscriber::set_global_default(builder.finish()),
+        LogFormat::Json => {
+            tracing::subscriber::set_global_default(builder.json().flatten_event(true).finish())
+        }
+    }?;
+    Ok(())
 }
@@ -0,0 +1,136 @@
+//! Manifests of the DICOM instances of a series received during an association.
+use crate::findscu_cache::PacsSeriesInfo;
+use crate::redact::{redacted, redacted_message};
+use crate::storage::DicomStorage;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+use ulid::Ulid;
+
+/// Contents of a JSON file written to the directory of a series when its association finishes.
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub(crate) struct SeriesManifest {
+    pub association_ulid: String,
+    pub pacs_name: String,
+    /// Label of the _oxidicom_ deployment, see `OXIDICOM_LABEL`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    pub StudyInstanceUID: String,
+    pub SeriesInstanceUID: String,
+    /// Number of DICOM instances stored
+    pub ndicom: usize,
+    /// Number of DICOM instances which the PACS says the series has, if known
+    pub NumberOfSeriesRelatedInstances: Option<usize>,
+    /// The DICOM instances stored, sorted by path
+    pub instances: Vec<ManifestInstance>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Debug, Serialize)]
+pub(crate) struct ManifestInstance {
+    pub SOPInstanceUID: String,
+    pub path: String,
+}
+
+impl SeriesManifest {
+    /// Name of the manifest file, which is written to the directory of the series.
+    pub fn file_name(association_ulid: Ulid) -> String {
+        format!("oxidicom-manifest-{association_ulid}.json")
+    }
+}
+
+/// A [SeriesManifest] to write once the DICOM instances of its series are stored.
+pub(crate) struct PendingManifest {
+    pub path: String,
+    /// Manifest listing every DICOM instance received for the series
+    pub manifest: SeriesManifest,
+    /// What the PACS reported about the series
+    pub pacs_info: PacsSeriesInfo,
+    pub storage: Arc<dyn DicomStorage>,
+}
+
+impl PendingManifest {
+    /// Write the manifest to storage, listing only the DICOM instances at the `stored` paths.
+    /// Errors are logged.
+    pub async fn write(self, stored: &HashSet<String>) {
+        let Self {
+            path,
+            mut manifest,
+            pacs_info,
+            storage,
+        } = self;
+        manifest
+            .instances
+            .retain(|instance| stored.contains(&instance.path));
+        manifest.ndicom = manifest.instances.len();
+        manifest.NumberOfSeriesRelatedInstances = pacs_info
+            .await
+            .map(|info| info.number_of_series_related_instances);
+        let data = serde_json::to_vec_pretty(&manifest).unwrap();
+        let result = tokio::task::spawn_blocking(move || storage.write_file(&path, &data)).await;
+        match result {
+            Ok(Ok(location)) => {
+                tracing::info!(event = "manifest", path = redacted(&location).as_ref())
+            }
+            Ok(Err(e)) => tracing::error!(
+                event = "manifest",
+                error = redacted_message(&e.to_string()).as_ref()
+            ),
+            Err(e) => tracing::error!(event = "manifest", error = e.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize_manifest() {
+        let manifest = SeriesManifest {
+            association_ulid: "01HZX1G7KNCYPXH4FXZ9W4Y3EM".to_string(),
+            pacs_name: "ORTHANC".to_string(),
+            label: None,
+            StudyInstanceUID: "1.2.3".to_string(),
+            SeriesInstanceUID: "1.2.3.4".to_string(),
+            ndicom: 1,
+            NumberOfSeriesRelatedInstances: None,
+            instances: vec![ManifestInstance {
+                SOPInstanceUID: "1.2.3.4.5".to_string(),
+                path: "SERVICES/PACS/ORTHANC/1.2.3.4/1.2.3.4.5.dcm".to_string(),
+            }],
+        };
+        let expected = serde_json::json!({
+            "association_ulid": "01HZX1G7KNCYPXH4FXZ9W4Y3EM",
+            "pacs_name": "ORTHANC",
+            "StudyInstanceUID": "1.2.3",
+            "SeriesInstanceUID": "1.2.3.4",
+            "ndicom": 1,
+            "NumberOfSeriesRelatedInstances": null,
+            "instances": [
+                {
+                    "SOPInstanceUID": "1.2.3.4.5",
+                    "path": "SERVICES/PACS/ORTHANC/1.2.3.4/1.2.3.4.5.dcm"
+                }
+            ]
+        });
+        assert_eq!(serde_json::to_value(&manifest).unwrap(), expected)
+    }
+
+    #[test]
+    fn test_serialize_manifest_label() {
+        let manifest = SeriesManifest {
+            association_ulid: "01HZX1G7KNCYPXH4FXZ9W4Y3EM".to_string(),
+            pacs_name: "ORTHANC".to_string(),
+            label: Some("env=staging".to_string()),
+            StudyInstanceUID: "1.2.3".to_string(),
+            SeriesInstanceUID: "1.2.3.4".to_string(),
+            ndicom: 0,
+            NumberOfSeriesRelatedInstances: Some(2),
+            instances: Vec::new(),
+        };
+        let actual = serde_json::to_value(&manifest).unwrap();
+        assert_eq!(actual["label"], "env=staging")
+    }
+}
@@ -0,0 +1,116 @@
+//! Prometheus metrics, served over HTTP at `/metrics`.
+use axum::http::header::CONTENT_TYPE;
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+
+/// Metrics recorded throughout oxidicom.
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// Number of associations which were established
+    pub associations_started: IntCounter,
+    /// Number of associations which ended
+    pub associations_finished: IntCounter,
+    /// Number of DICOM instances received over DICOM
+    pub instances_received: IntCounter,
+    /// Number of DICOM instances completely received by the listener, labeled by `transfer_syntax`
+    pub instances_by_transfer_syntax: IntCounterVec,
+    /// Number of DICOM files written to storage
+    pub files_written: IntCounter,
+    /// Total size of DICOM files written to storage
+    pub bytes_written: IntCounter,
+    /// Number of files registered to CUBE's database
+    pub files_registered: IntCounter,
+    /// Seconds from the first DICOM instance of a series until the end of its association,
+    /// labeled by `pacs_name` and `Modality`
+    pub series_duration: HistogramVec,
+    /// Number of jobs waiting for a worker of a [crate::thread_pool::ThreadPool], labeled by `pool`
+    pub thread_pool_queued: IntGaugeVec,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("oxidicom".to_string()), None)?;
+        let counter = |name: &str, help: &str| {
+            let c = IntCounter::new(name, help)?;
+            registry.register(Box::new(c.clone()))?;
+            Ok::<_, prometheus::Error>(c)
+        };
+        Ok(Self {
+            associations_started: counter("associations_started_total", "Associations started")?,
+            associations_finished: counter("associations_finished_total", "Associations finished")?,
+            instances_received: counter("instances_received_total", "DICOM instances received")?,
+            instances_by_transfer_syntax: {
+                let opts = Opts::new(
+                    "instances_by_transfer_syntax_total",
+                    "DICOM instances received by negotiated transfer syntax UID",
+                );
+                let c = IntCounterVec::new(opts, &["transfer_syntax"])?;
+                registry.register(Box::new(c.clone()))?;
+                c
+            },
+            files_written: counter("files_written_total", "DICOM files written to storage")?,
+            bytes_written: counter("bytes_written_total", "Bytes of DICOM written to storage")?,
+            files_registered: counter("files_registered_total", "Files registered to CUBE")?,
+            series_duration: {
+                let opts = HistogramOpts::new(
+                    "series_duration_seconds",
+                    "Time to receive a series in an association",
+                )
+                .buckets(prometheus::exponential_buckets(0.5, 2.0, 12)?);
+                let h = HistogramVec::new(opts, &["pacs_name", "Modality"])?;
+                registry.register(Box::new(h.clone()))?;
+                h
+            },
+            thread_pool_queued: {
+                let opts = Opts::new(
+                    "thread_pool_queued_jobs",
+                    "Jobs waiting for a worker thread, e.g. associations when all listener threads are busy",
+                );
+                let g = IntGaugeVec::new(opts, &["pool"])?;
+                registry.register(Box::new(g.clone()))?;
+                g
+            },
+            registry,
+        })
+    }
+
+    /// Encode all metrics in the Prometheus text exposition format.
+    fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buf = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buf)?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+}
+
+/// Get the global [Metrics].
+pub(crate) fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("Invalid metric definition"))
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Serve [metrics] at `/metrics` forever.
+pub(crate) async fn metrics_server(address: SocketAddr) -> anyhow::Result<()> {
+    let app = Router::new().route("/metrics", get(get_metrics));
+    let listener = tokio::net::TcpListener::bind(address).await?;
+    tracing::info!(metrics_address = address.to_string(), "Serving metrics");
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn get_metrics() -> impl IntoResponse {
+    match metrics().encode() {
+        Ok(body) => Ok(([(CONTENT_TYPE, prometheus::TEXT_FORMAT)], body)),
+        Err(e) => {
+            tracing::error!(error = e.to_string());
+            Err(axum::http::StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
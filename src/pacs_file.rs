@@ -6,20 +6,53 @@
 //! https://github.com/FNNDSC/pypx/blob/7b83154d7c6d631d81eac8c9c4a2fc164ccc2ebc/pypx/register.py#L459-L465
 #![allow(non_snake_case)]
 
+use std::borrow::Cow;
 use std::fmt::Display;
 
 use crate::dicomrs_settings::ClientAETitle;
+use camino::Utf8PathBuf;
 use dicom::dictionary_std::tags;
 use dicom::object::{DefaultDicomObject, Tag};
+use encoding::all::ISO_2022_JP;
+use encoding::{DecoderTrap, Encoding};
 
 use crate::error::{name_of, DicomRequiredTagError, RequiredTagError};
+use crate::path_template::{PathTemplate, Placeholder};
 use crate::patient_age::parse_age;
-use crate::sanitize::sanitize_path;
+use crate::redact::redacted_tag_value;
+use crate::sanitize::{sanitize_path, sanitize_path_distinct, truncate_path_components};
+use crate::storage::Compression;
+use md5::{Digest, Md5};
+use std::num::NonZeroUsize;
+use time::format_description::OwnedFormatItem;
 
 /// A wrapper of [PacsFileRegistrationRequest] along with the [DefaultDicomObject] it was created from.
 pub struct PacsFileRegistration {
     pub request: PacsFileRegistrationRequest,
     pub obj: DefaultDicomObject,
+    /// How `obj` should be written to storage
+    pub storage: StoredData,
+}
+
+/// How the DICOM object of a [PacsFileRegistration] should be written to storage.
+pub(crate) enum StoredData {
+    /// Encode the DICOM object.
+    Encode,
+    /// Write the file meta group followed by the data set exactly as it was received.
+    Raw(Vec<u8>),
+    /// The DICOM file was already written to this temporary path as it was received.
+    Spooled(Utf8PathBuf),
+}
+
+impl StoredData {
+    /// Delete the temporary file, if any.
+    pub fn discard(self) {
+        if let StoredData::Spooled(path) = self {
+            if let Err(e) = fs_err::remove_file(path) {
+                tracing::warn!(message = e.to_string());
+            }
+        }
+    }
 }
 
 impl PacsFileRegistration {
@@ -27,14 +60,68 @@ impl PacsFileRegistration {
     pub(crate) fn new(
         pacs_name: ClientAETitle,
         obj: DefaultDicomObject,
+        options: &PacsFileOptions,
     ) -> Result<(Self, Vec<BadTag>), DicomRequiredTagError> {
-        match PacsFileRegistrationRequest::new(pacs_name, &obj) {
-            Ok((request, bad_tags)) => Ok((Self { request, obj }, bad_tags)),
-            Err(error) => Err(DicomRequiredTagError { obj, error }),
+        match PacsFileRegistrationRequest::new(pacs_name, &obj, options) {
+            Ok((request, bad_tags)) => {
+                let pacs_file = Self {
+                    request,
+                    obj,
+                    storage: StoredData::Encode,
+                };
+                Ok((pacs_file, bad_tags))
+            }
+            Err(error) => Err(DicomRequiredTagError {
+                obj: Box::new(obj),
+                error,
+            }),
         }
     }
 }
 
+/// Options for how a [PacsFileRegistrationRequest] is created from a DICOM object.
+#[derive(Debug)]
+pub(crate) struct PacsFileOptions {
+    /// Template for the path. If `None`, the `px-push` format of pypx is used.
+    pub path_template: Option<PathTemplate>,
+    /// Non-standard formats to try when `StudyDate` is not a valid DICOM date.
+    pub extra_date_formats: Vec<DateFormat>,
+    /// If true, a missing `PatientID` or missing/invalid `StudyDate` is replaced with
+    /// [MISSING_PATIENT_ID] or [MISSING_STUDY_DATE] and reported as a [BadTag].
+    pub allow_missing_tags: bool,
+    /// If true, missing `Modality` and `SeriesDescription` are filled in from the PACS,
+    /// which is asked for them along with `NumberOfSeriesRelatedInstances`.
+    pub fill_from_pacs: bool,
+    /// Maximum length in bytes of each component of the path, see [truncate_path_components].
+    pub max_path_component_length: NonZeroUsize,
+    /// Compression of stored DICOM files, which determines the extension of their paths.
+    pub compression: Compression,
+    /// Algorithm for the hash in the series directory name of the default path.
+    pub series_hash: SeriesHash,
+    /// If true, values which are changed by sanitization have a short hash appended,
+    /// see [sanitize_path_distinct]. Otherwise, paths are the same as `px-push`.
+    pub sanitize_hash: bool,
+}
+
+/// `PatientID` used in place of a missing value, see [PacsFileOptions::allow_missing_tags].
+pub(crate) const MISSING_PATIENT_ID: &str = "NO_PATIENT_ID";
+
+/// `StudyDate` used in place of a missing value, see [PacsFileOptions::allow_missing_tags].
+pub(crate) const MISSING_STUDY_DATE: time::Date = time::macros::date!(1970 - 01 - 01);
+
+/// A [time] format description, e.g. `[day]/[month]/[year]`.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct DateFormat(OwnedFormatItem);
+
+impl TryFrom<String> for DateFormat {
+    type Error = time::error::InvalidFormatDescription;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        time::format_description::parse_owned::<2>(&value).map(Self)
+    }
+}
+
 /// Data necessary to register a DICOM file to CUBE's database in the `pacsfiles_pacsfile` table.
 ///
 /// Historically, this struct represented the JSON payload to `POST api/v1/pacs/`. However,
@@ -67,29 +154,55 @@ pub struct BadTag {
 
 impl Display for BadTag {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}={:?}", name_of(&self.tag), self.value)
+        let value = self
+            .value
+            .as_deref()
+            .map(|value| redacted_tag_value(self.tag, value));
+        write!(f, "{}={:?}", name_of(&self.tag), value)
     }
 }
 
 impl PacsFileRegistrationRequest {
-    pub fn new(
+    /// Extract the metadata of a DICOM object.
+    ///
+    /// The path is produced from [PacsFileOptions::path_template] if given,
+    /// otherwise the `px-push` format of pypx is used.
+    pub(crate) fn new(
         pacs_name: ClientAETitle,
         dcm: &DefaultDicomObject,
+        options: &PacsFileOptions,
     ) -> Result<(Self, Vec<BadTag>), RequiredTagError> {
         let mut bad_tags = vec![];
         // required fields
         let StudyInstanceUID = ttr(dcm, tags::STUDY_INSTANCE_UID)?;
         let SeriesInstanceUID = ttr(dcm, tags::SERIES_INSTANCE_UID)?;
         let SOPInstanceUID = ttr(dcm, tags::SOP_INSTANCE_UID)?;
-        let PatientID = ttr(dcm, tags::PATIENT_ID)?;
-        let StudyDate_string = ttr(dcm, tags::STUDY_DATE)?; // required by CUBE
-        let StudyDate_format = time::macros::format_description!("[year][month][day]"); // DICOM DA format
-        let StudyDate = time::Date::parse(&StudyDate_string, &StudyDate_format).map_err(|_| {
-            RequiredTagError::Bad(BadTag {
-                tag: tags::STUDY_DATE,
-                value: Some(StudyDate_string.to_string()),
-            })
-        })?;
+        let allow_missing = options.allow_missing_tags;
+        let PatientID = match ttr(dcm, tags::PATIENT_ID) {
+            Err(_) if allow_missing => {
+                bad_tags.push(BadTag {
+                    tag: tags::PATIENT_ID,
+                    value: None,
+                });
+                MISSING_PATIENT_ID.to_string()
+            }
+            result => result?,
+        };
+        // required by CUBE
+        let StudyDate_string = match ttr(dcm, tags::STUDY_DATE) {
+            Err(_) if allow_missing => String::new(),
+            result => result?,
+        };
+        let StudyDate = match parse_study_date(&StudyDate_string, &options.extra_date_formats) {
+            Err(_) if allow_missing => {
+                bad_tags.push(BadTag {
+                    tag: tags::STUDY_DATE,
+                    value: tts(dcm, tags::STUDY_DATE),
+                });
+                MISSING_STUDY_DATE
+            }
+            result => result?,
+        };
 
         // optional values
         let PatientName = tts(dcm, tags::PATIENT_NAME);
@@ -116,26 +229,58 @@ impl PacsFileRegistrationRequest {
             num
         });
 
-        // https://github.com/FNNDSC/pypx/blob/7b83154d7c6d631d81eac8c9c4a2fc164ccc2ebc/bin/px-push#L175-L195
-        let path = format!(
-            "SERVICES/PACS/{}/{}-{}-{}/{}-{}-{}/{:0>5}-{}-{}/{:0>4}-{}.dcm",
-            sanitize_path(&pacs_name),
-            // Patient
-            sanitize_path(PatientID.as_str()),
-            sanitize_path(PatientName.as_deref().unwrap_or("")),
-            sanitize_path(PatientBirthDate.as_deref().unwrap_or("")),
-            // Study
-            sanitize_path(StudyDescription.as_deref().unwrap_or("StudyDescription")),
-            sanitize_path(AccessionNumber.as_deref().unwrap_or("AccessionNumber")),
-            sanitize_path(StudyDate_string.as_str()),
-            // Series
-            SeriesNumber.unwrap_or_else(|| MaybeU32::String("SeriesNumber".to_string())),
-            sanitize_path(SeriesDescription.as_deref().unwrap_or("SeriesDescription")),
-            &hash(SeriesInstanceUID.as_str())[..7],
-            // Instance
-            InstanceNumber.unwrap_or_else(|| MaybeU32::String("InstanceNumber".to_string())),
-            sanitize_path(SOPInstanceUID)
-        );
+        let sanitize = |s: &str| {
+            if options.sanitize_hash {
+                sanitize_path_distinct(s)
+            } else {
+                sanitize_path(s)
+            }
+        };
+        let path = if let Some(template) = &options.path_template {
+            let fname = template.render(
+                |placeholder| match placeholder {
+                    Placeholder::PatientID => &PatientID,
+                    Placeholder::PatientName => PatientName.as_deref().unwrap_or(""),
+                    Placeholder::PatientBirthDate => PatientBirthDate.as_deref().unwrap_or(""),
+                    Placeholder::StudyDate => &StudyDate_string,
+                    Placeholder::StudyTime => tt(dcm, tags::STUDY_TIME).unwrap_or(""),
+                    Placeholder::StudyDescription => StudyDescription.as_deref().unwrap_or(""),
+                    Placeholder::AccessionNumber => AccessionNumber.as_deref().unwrap_or(""),
+                    Placeholder::StudyInstanceUID => &StudyInstanceUID,
+                    Placeholder::SeriesNumber => tt(dcm, tags::SERIES_NUMBER).unwrap_or(""),
+                    Placeholder::SeriesDescription => SeriesDescription.as_deref().unwrap_or(""),
+                    Placeholder::SeriesTime => tt(dcm, tags::SERIES_TIME).unwrap_or(""),
+                    Placeholder::SeriesInstanceUID => &SeriesInstanceUID,
+                    Placeholder::InstanceNumber => tt(dcm, tags::INSTANCE_NUMBER).unwrap_or(""),
+                    Placeholder::SOPInstanceUID => &SOPInstanceUID,
+                },
+                sanitize,
+            );
+            format!("SERVICES/PACS/{}/{}", sanitize_path(&pacs_name), fname)
+        } else {
+            // https://github.com/FNNDSC/pypx/blob/7b83154d7c6d631d81eac8c9c4a2fc164ccc2ebc/bin/px-push#L175-L195
+            format!(
+                "SERVICES/PACS/{}/{}-{}-{}/{}-{}-{}/{:0>5}-{}-{}/{:0>4}-{}.dcm",
+                sanitize_path(&pacs_name),
+                // Patient
+                sanitize(PatientID.as_str()),
+                sanitize(PatientName.as_deref().unwrap_or("")),
+                sanitize(PatientBirthDate.as_deref().unwrap_or("")),
+                // Study
+                sanitize(StudyDescription.as_deref().unwrap_or("StudyDescription")),
+                sanitize(AccessionNumber.as_deref().unwrap_or("AccessionNumber")),
+                sanitize(StudyDate_string.as_str()),
+                // Series
+                SeriesNumber.unwrap_or_else(|| MaybeU32::String("SeriesNumber".to_string())),
+                sanitize(SeriesDescription.as_deref().unwrap_or("SeriesDescription")),
+                options.series_hash.hash(SeriesInstanceUID.as_str()),
+                // Instance
+                InstanceNumber.unwrap_or_else(|| MaybeU32::String("InstanceNumber".to_string())),
+                sanitize_path(&SOPInstanceUID)
+            )
+        };
+        let path = format!("{path}{}", options.compression.extension());
+        let path = truncate_path_components(&path, options.max_path_component_length.get());
 
         let pacs_file = Self {
             path,
@@ -158,14 +303,69 @@ impl PacsFileRegistrationRequest {
     }
 }
 
+/// Parse `StudyDate`, which should be in the DICOM DA format `YYYYMMDD`.
+///
+/// Dates in the format `YYYY-MM-DD`, or any of `extra_formats` (tried in order),
+/// are accepted with a warning.
+fn parse_study_date(
+    value: &str,
+    extra_formats: &[DateFormat],
+) -> Result<time::Date, RequiredTagError> {
+    let da_format = time::macros::format_description!("[year][month][day]");
+    if let Ok(date) = time::Date::parse(value, &da_format) {
+        return Ok(date);
+    }
+    let iso_format = time::macros::format_description!("[year]-[month]-[day]");
+    let date = time::Date::parse(value, &iso_format).ok().or_else(|| {
+        extra_formats
+            .iter()
+            .find_map(|format| time::Date::parse(value, &format.0).ok())
+    });
+    if let Some(date) = date {
+        tracing::warn!(
+            StudyDate = value,
+            "StudyDate is not in the DICOM DA format."
+        );
+        Ok(date)
+    } else {
+        Err(RequiredTagError::Bad(BadTag {
+            tag: tags::STUDY_DATE,
+            value: Some(value.to_string()),
+        }))
+    }
+}
+
 /// Required string tag
 fn ttr(dcm: &DefaultDicomObject, tag: Tag) -> Result<String, RequiredTagError> {
-    tts(dcm, tag).ok_or_else(|| RequiredTagError::Missing(tag))
+    tts(dcm, tag).ok_or(RequiredTagError::Missing(tag))
 }
 
-/// Optional string tag (with null bytes removed)
+/// Optional string tag (with null bytes removed), decoded according to its `SpecificCharacterSet`
 fn tts(dcm: &DefaultDicomObject, tag: Tag) -> Option<String> {
-    tt(dcm, tag).map(|s| s.replace('\0', ""))
+    tt(dcm, tag).map(|s| decode_iso2022(dcm, s).replace('\0', ""))
+}
+
+/// Decode a value which uses ISO 2022 escape sequences to switch to JIS X 0208 (`ISO 2022 IR 87`).
+///
+/// _dicom-rs_ decodes the character sets `ISO_IR 100`, `ISO_IR 192`, etc. while reading the
+/// DICOM object, but it ignores code extensions. Those values are still 7-bit, so they can be
+/// decoded again.
+fn decode_iso2022<'a>(dcm: &DefaultDicomObject, value: &'a str) -> Cow<'a, str> {
+    if !value.contains('\x1b') {
+        return Cow::Borrowed(value);
+    }
+    let is_jis = dcm
+        .element(tags::SPECIFIC_CHARACTER_SET)
+        .ok()
+        .and_then(|e| e.strings().ok())
+        .is_some_and(|charsets| charsets.iter().any(|c| c.trim() == "ISO 2022 IR 87"));
+    if !is_jis {
+        return Cow::Borrowed(value);
+    }
+    ISO_2022_JP
+        .decode(value.as_bytes(), DecoderTrap::Replace)
+        .map(Cow::Owned)
+        .unwrap_or(Cow::Borrowed(value))
 }
 
 /// Try to get the trimmed string value of a DICOM object.
@@ -202,7 +402,310 @@ impl Display for MaybeU32 {
     }
 }
 
-/// Produces the hash of the data as a hexidecimal string.
-fn hash(data: &str) -> String {
-    format!("{:x}", seahash::hash(data.as_bytes()))
+/// Algorithm for the hash of `SeriesInstanceUID` in the series directory name of the default path.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeriesHash {
+    /// [seahash], which is what oxidicom has always used.
+    #[default]
+    Seahash,
+    /// MD5, which is what pypx uses.
+    Md5,
+}
+
+impl SeriesHash {
+    /// Produces the first 7 hexadecimal digits of the hash of the data.
+    fn hash(&self, data: &str) -> String {
+        let hash = match self {
+            SeriesHash::Seahash => format!("{:x}", seahash::hash(data.as_bytes())),
+            SeriesHash::Md5 => hex::encode(Md5::digest(data.as_bytes())),
+        };
+        hash[..7].to_string()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::dictionary_std::uids;
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+    use rstest::*;
+    use time::macros::date;
+
+    #[rstest]
+    #[case("20240618", date!(2024 - 06 - 18))]
+    #[case("2024-06-18", date!(2024 - 06 - 18))]
+    #[case("18/06/2024", date!(2024 - 06 - 18))]
+    fn test_parse_study_date(#[case] value: &str, #[case] expected: time::Date) {
+        let extra_formats = [DateFormat::try_from("[day]/[month]/[year]".to_string()).unwrap()];
+        assert_eq!(parse_study_date(value, &extra_formats).unwrap(), expected)
+    }
+
+    #[rstest]
+    #[case("18/06/2024")]
+    #[case("20241318")]
+    #[case("")]
+    fn test_parse_bad_study_date(#[case] value: &str) {
+        assert!(parse_study_date(value, &[]).is_err())
+    }
+
+    pub(crate) fn example_dcm(series_description: &str) -> DefaultDicomObject {
+        let element = |tag, vr, value: &str| DataElement::new(tag, vr, PrimitiveValue::from(value));
+        InMemDicomObject::from_element_iter([
+            element(tags::PATIENT_ID, VR::LO, "1234"),
+            element(tags::STUDY_DATE, VR::DA, "20240618"),
+            element(tags::STUDY_INSTANCE_UID, VR::UI, "1.2.3"),
+            element(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3.4"),
+            element(tags::SERIES_DESCRIPTION, VR::LO, series_description),
+            element(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5"),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uids::CT_IMAGE_STORAGE)
+                .media_storage_sop_instance_uid("1.2.3.4.5")
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )
+        .unwrap()
+    }
+
+    pub(crate) fn example_options() -> PacsFileOptions {
+        PacsFileOptions {
+            path_template: None,
+            extra_date_formats: vec![],
+            allow_missing_tags: false,
+            fill_from_pacs: false,
+            max_path_component_length: NonZeroUsize::new(255).unwrap(),
+            compression: Compression::None,
+            series_hash: SeriesHash::Seahash,
+            sanitize_hash: false,
+        }
+    }
+
+    #[rstest]
+    #[case("ISO_IR 100", b"M\xfcller^Hans", "Müller^Hans")]
+    #[case(
+        "\\ISO 2022 IR 87",
+        b"Yamada^Tarou=\x1b$B;3ED\x1b(B^\x1b$BB@O:\x1b(B",
+        "Yamada^Tarou=山田^太郎"
+    )]
+    #[case("", b"Doe^Jane", "Doe^Jane")]
+    fn test_specific_character_set(
+        #[case] charset: &str,
+        #[case] patient_name: &[u8],
+        #[case] expected: &str,
+    ) {
+        let element = |tag: Tag, vr: &[u8; 2], value: &[u8]| {
+            let mut value = value.to_vec();
+            if value.len() % 2 == 1 {
+                value.push(b' ');
+            }
+            let mut bytes = Vec::with_capacity(8 + value.len());
+            bytes.extend(tag.group().to_le_bytes());
+            bytes.extend(tag.element().to_le_bytes());
+            bytes.extend(vr);
+            bytes.extend((value.len() as u16).to_le_bytes());
+            bytes.extend(value);
+            bytes
+        };
+        let data = [
+            element(tags::SPECIFIC_CHARACTER_SET, b"CS", charset.as_bytes()),
+            element(tags::SOP_INSTANCE_UID, b"UI", b"1.2.3.4.5"),
+            element(tags::STUDY_DATE, b"DA", b"20240618"),
+            element(tags::PATIENT_NAME, b"PN", patient_name),
+            element(tags::PATIENT_ID, b"LO", b"1234"),
+            element(tags::STUDY_INSTANCE_UID, b"UI", b"1.2.3"),
+            element(tags::SERIES_INSTANCE_UID, b"UI", b"1.2.3.4"),
+        ]
+        .concat();
+        let ts = dicom::transfer_syntax::entries::EXPLICIT_VR_LITTLE_ENDIAN.erased();
+        let dcm = InMemDicomObject::read_dataset_with_ts(data.as_slice(), &ts)
+            .unwrap()
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid(uids::CT_IMAGE_STORAGE)
+                    .media_storage_sop_instance_uid("1.2.3.4.5")
+                    .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+            )
+            .unwrap();
+        let (request, _) =
+            PacsFileRegistrationRequest::new("ORTHANC".into(), &dcm, &example_options()).unwrap();
+        assert_eq!(request.PatientName.as_deref(), Some(expected));
+        let patient_dir = request.path.split('/').nth(3).unwrap();
+        assert!(patient_dir.starts_with(&format!("1234-{}", sanitize_path(expected))));
+    }
+
+    #[test]
+    fn test_long_series_description() {
+        let options = example_options();
+        let path_of = |series_description: &str| {
+            let dcm = example_dcm(series_description);
+            PacsFileRegistrationRequest::new(ClientAETitle::from_static("ORTHANC"), &dcm, &options)
+                .unwrap()
+                .0
+                .path
+        };
+        let a = path_of(&"A".repeat(300));
+        let b = path_of(&format!("{}B", "A".repeat(300)));
+        let series_dir = a.split('/').nth(5).unwrap();
+        assert_eq!(series_dir.len(), 255);
+        assert!(series_dir.starts_with("SeriesNumber-AAAA"));
+        assert!(a.ends_with("/InstanceNumber-1.2.3.4.5.dcm"));
+        assert_ne!(a, b);
+        assert!(path_of("Chest").contains("/SeriesNumber-Chest-eaf4f78/"));
+    }
+
+    #[test]
+    fn test_compressed_path() {
+        let options = PacsFileOptions {
+            compression: Compression::Gzip,
+            ..example_options()
+        };
+        let dcm = example_dcm("Chest");
+        let (request, _) =
+            PacsFileRegistrationRequest::new(ClientAETitle::from_static("ORTHANC"), &dcm, &options)
+                .unwrap();
+        assert!(request.path.ends_with("/InstanceNumber-1.2.3.4.5.dcm.gz"))
+    }
+
+    #[test]
+    fn test_path_template_times() {
+        let template =
+            "{StudyDate}-{StudyTime}/{SeriesInstanceUID}/{SeriesTime}-{SOPInstanceUID}.dcm";
+        let options = PacsFileOptions {
+            path_template: Some(PathTemplate::try_from(template.to_string()).unwrap()),
+            ..example_options()
+        };
+        let mut dcm = example_dcm("Chest");
+        dcm.put(DataElement::new(
+            tags::STUDY_TIME,
+            VR::TM,
+            PrimitiveValue::from("143015.123"),
+        ));
+        let (request, _) =
+            PacsFileRegistrationRequest::new(ClientAETitle::from_static("ORTHANC"), &dcm, &options)
+                .unwrap();
+        assert_eq!(
+            request.path,
+            "SERVICES/PACS/ORTHANC/20240618-143015.123/1.2.3.4/-1.2.3.4.5.dcm"
+        )
+    }
+
+    #[rstest]
+    #[case(SeriesHash::Seahash, "/SeriesNumber-Chest-eaf4f78/")]
+    #[case(SeriesHash::Md5, "/SeriesNumber-Chest-6465ec7/")]
+    fn test_series_hash(#[case] series_hash: SeriesHash, #[case] expected: &str) {
+        let options = PacsFileOptions {
+            series_hash,
+            ..example_options()
+        };
+        let dcm = example_dcm("Chest");
+        let (request, _) =
+            PacsFileRegistrationRequest::new(ClientAETitle::from_static("ORTHANC"), &dcm, &options)
+                .unwrap();
+        assert!(request.path.contains(expected))
+    }
+
+    #[rstest]
+    #[case(
+        Some("Doe^Jane"),
+        Some("Chest"),
+        Some("A123"),
+        "SERVICES/PACS/ORTHANC/1234-Doe_Jane-/StudyDescription-A123-20240618/SeriesNumber-Chest-eaf4f78/InstanceNumber-1.2.3.4.5.dcm"
+    )]
+    #[case(
+        Some("O'Brien^Conan"),
+        Some("Chest"),
+        Some("A123"),
+        "SERVICES/PACS/ORTHANC/1234-O_Brien_Conan-/StudyDescription-A123-20240618/SeriesNumber-Chest-eaf4f78/InstanceNumber-1.2.3.4.5.dcm"
+    )]
+    #[case(
+        Some("李^雷"),
+        Some("Chest"),
+        Some("A123"),
+        "SERVICES/PACS/ORTHANC/1234-_-/StudyDescription-A123-20240618/SeriesNumber-Chest-eaf4f78/InstanceNumber-1.2.3.4.5.dcm"
+    )]
+    #[case(
+        Some("Doe/Jane"),
+        Some("Chest"),
+        Some("A123"),
+        "SERVICES/PACS/ORTHANC/1234-Doe_Jane-/StudyDescription-A123-20240618/SeriesNumber-Chest-eaf4f78/InstanceNumber-1.2.3.4.5.dcm"
+    )]
+    #[case(
+        Some("Doe^Jane"),
+        Some(""),
+        Some("A123"),
+        "SERVICES/PACS/ORTHANC/1234-Doe_Jane-/StudyDescription-A123-20240618/SeriesNumber--eaf4f78/InstanceNumber-1.2.3.4.5.dcm"
+    )]
+    #[case(
+        Some("Doe^Jane"),
+        Some("Chest"),
+        None,
+        "SERVICES/PACS/ORTHANC/1234-Doe_Jane-/StudyDescription-AccessionNumber-20240618/SeriesNumber-Chest-eaf4f78/InstanceNumber-1.2.3.4.5.dcm"
+    )]
+    #[case(
+        None,
+        Some("T1 / MPRAGE"),
+        None,
+        "SERVICES/PACS/ORTHANC/1234--/StudyDescription-AccessionNumber-20240618/SeriesNumber-T1_MPRAGE-eaf4f78/InstanceNumber-1.2.3.4.5.dcm"
+    )]
+    fn test_pypx_path(
+        #[case] patient_name: Option<&str>,
+        #[case] series_description: Option<&str>,
+        #[case] accession_number: Option<&str>,
+        #[case] expected: &str,
+    ) {
+        let mut dcm = example_dcm("");
+        let element = |tag, vr, value: &str| DataElement::new(tag, vr, PrimitiveValue::from(value));
+        dcm.remove_element(tags::SERIES_DESCRIPTION);
+        for (tag, vr, value) in [
+            (tags::PATIENT_NAME, VR::PN, patient_name),
+            (tags::SERIES_DESCRIPTION, VR::LO, series_description),
+            (tags::ACCESSION_NUMBER, VR::SH, accession_number),
+        ] {
+            if let Some(value) = value {
+                dcm.put(element(tag, vr, value));
+            }
+        }
+        let (request, _) = PacsFileRegistrationRequest::new(
+            ClientAETitle::from_static("ORTHANC"),
+            &dcm,
+            &example_options(),
+        )
+        .unwrap();
+        assert_eq!(request.path, expected)
+    }
+
+    #[rstest]
+    #[case("Doe^Jane", "1234-Doe_Jane_cce87f1-")]
+    #[case("Doe Jane", "1234-Doe_Jane_")]
+    #[case("Doe-Jane", "1234-Doe-Jane-")]
+    fn test_sanitize_hash_path(#[case] patient_name: &str, #[case] expected_prefix: &str) {
+        let options = PacsFileOptions {
+            sanitize_hash: true,
+            ..example_options()
+        };
+        let mut dcm = example_dcm("Chest");
+        dcm.put(DataElement::new(
+            tags::PATIENT_NAME,
+            VR::PN,
+            PrimitiveValue::from(patient_name),
+        ));
+        let (request, _) =
+            PacsFileRegistrationRequest::new(ClientAETitle::from_static("ORTHANC"), &dcm, &options)
+                .unwrap();
+        let patient_dir = request.path.split('/').nth(3).unwrap();
+        assert!(patient_dir.starts_with(expected_prefix), "{patient_dir}")
+    }
+
+    #[rstest]
+    // hashlib.md5(SeriesInstanceUID.encode()).hexdigest()[:7], as in pypx
+    #[case("1.2.840.113619.2.5.1762583153.215519.978957063.78", "8083425")]
+    #[case(
+        "1.3.12.2.1107.5.2.19.45152.2013030808061520200285270.0.0.0",
+        "bf7c8ff"
+    )]
+    fn test_md5_series_hash(#[case] series_instance_uid: &str, #[case] expected: &str) {
+        assert_eq!(SeriesHash::Md5.hash(series_instance_uid), expected)
+    }
 }
@@ -0,0 +1,139 @@
+//! Limiting the number of concurrent associations from, and C-FINDs to, each PACS.
+use crate::dicomrs_settings::ClientAETitle;
+use futures::future::{BoxFuture, FutureExt};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Counts the associations from each PACS, allowing a maximum number per AE title.
+pub(crate) struct PacsConcurrencyLimiter {
+    limits: HashMap<ClientAETitle, NonZeroUsize>,
+    default_limit: Option<NonZeroUsize>,
+    counts: Arc<Mutex<HashMap<ClientAETitle, usize>>>,
+}
+
+impl PacsConcurrencyLimiter {
+    /// AE titles missing from `limits` are limited to `default_limit`, or unlimited if [None].
+    pub fn new(
+        limits: HashMap<ClientAETitle, NonZeroUsize>,
+        default_limit: Option<NonZeroUsize>,
+    ) -> Self {
+        Self {
+            limits,
+            default_limit,
+            counts: Default::default(),
+        }
+    }
+
+    /// Take note of an association from `aec`, which lasts until the returned permit is dropped.
+    ///
+    /// Returns [None] if `aec` already has its maximum number of associations.
+    pub fn try_acquire(&self, aec: &ClientAETitle) -> Option<PacsPermit> {
+        let limit = self.limits.get(aec).or(self.default_limit.as_ref());
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(aec.clone()).or_default();
+        if limit.is_some_and(|limit| *count >= limit.get()) {
+            return None;
+        }
+        *count += 1;
+        Some(PacsPermit {
+            aec: aec.clone(),
+            counts: Arc::clone(&self.counts),
+        })
+    }
+}
+
+/// An association counted by [PacsConcurrencyLimiter].
+pub(crate) struct PacsPermit {
+    aec: ClientAETitle,
+    counts: Arc<Mutex<HashMap<ClientAETitle, usize>>>,
+}
+
+impl Drop for PacsPermit {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.aec) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.aec);
+            }
+        }
+    }
+}
+
+/// Limits the number of concurrent C-FINDs to each PACS, keyed by PACS address.
+pub(crate) struct FindScuLimiter {
+    limit: Option<NonZeroUsize>,
+    semaphores: HashMap<String, Arc<Semaphore>>,
+}
+
+impl FindScuLimiter {
+    /// If `limit` is [None], C-FINDs are unlimited.
+    pub fn new(limit: Option<NonZeroUsize>) -> Self {
+        Self {
+            limit,
+            semaphores: Default::default(),
+        }
+    }
+
+    /// Wait for a C-FIND to `pacs_address` to be allowed, which lasts until the permit is dropped.
+    pub fn acquire(
+        &mut self,
+        pacs_address: &str,
+    ) -> BoxFuture<'static, Option<OwnedSemaphorePermit>> {
+        let Some(limit) = self.limit else {
+            return futures::future::ready(None).boxed();
+        };
+        let semaphore = self
+            .semaphores
+            .entry(pacs_address.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(limit.get())));
+        Arc::clone(semaphore)
+            .acquire_owned()
+            .map(|permit| permit.ok())
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_acquire() {
+        let limits = HashMap::from([(ClientAETitle::from_static("BCH"), NonZeroUsize::MIN)]);
+        let limiter = PacsConcurrencyLimiter::new(limits, NonZeroUsize::new(2));
+        let bch = ClientAETitle::from_static("BCH");
+        let mgh = ClientAETitle::from_static("MGH");
+
+        let permit = limiter.try_acquire(&bch);
+        assert!(permit.is_some());
+        assert!(limiter.try_acquire(&bch).is_none());
+        drop(permit);
+        assert!(limiter.try_acquire(&bch).is_some());
+
+        let permits = [limiter.try_acquire(&mgh), limiter.try_acquire(&mgh)];
+        assert!(permits.iter().all(Option::is_some));
+        assert!(limiter.try_acquire(&mgh).is_none());
+    }
+
+    #[test]
+    fn test_findscu_limiter() {
+        let mut limiter = FindScuLimiter::new(NonZeroUsize::new(1));
+        let permit = limiter.acquire("pacs:4242").now_or_never().unwrap();
+        assert!(permit.is_some());
+        let mut waiting = limiter.acquire("pacs:4242");
+        assert!((&mut waiting).now_or_never().is_none());
+        assert!(limiter.acquire("other:4242").now_or_never().is_some());
+        drop(permit);
+        assert!(waiting.now_or_never().unwrap().is_some());
+
+        let mut unlimited = FindScuLimiter::new(None);
+        assert!(unlimited
+            .acquire("pacs:4242")
+            .now_or_never()
+            .unwrap()
+            .is_none());
+    }
+}
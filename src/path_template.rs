@@ -0,0 +1,315 @@
+//! Configurable storage paths for received DICOM files.
+
+/// A template for the path of a DICOM file, relative to `SERVICES/PACS/{pacs_name}/`.
+///
+/// The template may contain placeholders such as `{SeriesInstanceUID}`, which are replaced
+/// by the (sanitized) value of the DICOM tag. See [Placeholder] for the supported placeholders.
+///
+/// To ensure every DICOM file has a unique path, the file name must contain `{SOPInstanceUID}`
+/// and the directory must contain `{SeriesInstanceUID}`.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct PathTemplate(Vec<Segment>);
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Values which can be used in a [PathTemplate].
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Placeholder {
+    PatientID,
+    PatientName,
+    PatientBirthDate,
+    StudyDate,
+    StudyTime,
+    StudyDescription,
+    AccessionNumber,
+    StudyInstanceUID,
+    SeriesNumber,
+    SeriesDescription,
+    SeriesTime,
+    SeriesInstanceUID,
+    InstanceNumber,
+    SOPInstanceUID,
+}
+
+impl Placeholder {
+    const ALL: [Self; 14] = [
+        Self::PatientID,
+        Self::PatientName,
+        Self::PatientBirthDate,
+        Self::StudyDate,
+        Self::StudyTime,
+        Self::StudyDescription,
+        Self::AccessionNumber,
+        Self::StudyInstanceUID,
+        Self::SeriesNumber,
+        Self::SeriesDescription,
+        Self::SeriesTime,
+        Self::SeriesInstanceUID,
+        Self::InstanceNumber,
+        Self::SOPInstanceUID,
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|p| p.name() == name)
+    }
+
+    /// Name of the placeholder, which is also the name of its DICOM tag.
+    fn name(self) -> &'static str {
+        match self {
+            Self::PatientID => "PatientID",
+            Self::PatientName => "PatientName",
+            Self::PatientBirthDate => "PatientBirthDate",
+            Self::StudyDate => "StudyDate",
+            Self::StudyTime => "StudyTime",
+            Self::StudyDescription => "StudyDescription",
+            Self::AccessionNumber => "AccessionNumber",
+            Self::StudyInstanceUID => "StudyInstanceUID",
+            Self::SeriesNumber => "SeriesNumber",
+            Self::SeriesDescription => "SeriesDescription",
+            Self::SeriesTime => "SeriesTime",
+            Self::SeriesInstanceUID => "SeriesInstanceUID",
+            Self::InstanceNumber => "InstanceNumber",
+            Self::SOPInstanceUID => "SOPInstanceUID",
+        }
+    }
+}
+
+/// Error parsing a [PathTemplate].
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum PathTemplateError {
+    #[error("Unknown placeholder in path template: {{{0}}}")]
+    UnknownPlaceholder(String),
+    #[error("Unclosed placeholder in path template")]
+    Unclosed,
+    #[error("Path template must not be absolute nor contain \"..\"")]
+    Escapes,
+    #[error("Path template must not contain empty nor \".\" directory or file names")]
+    EmptyComponent,
+    #[error("File name of path template must contain {{SOPInstanceUID}}")]
+    NotInstanceUnique,
+    #[error("Directory of path template must contain {{SeriesInstanceUID}}")]
+    NotSeriesUnique,
+}
+
+impl PathTemplate {
+    /// Produce a path, replacing every placeholder with the value from `value_of`
+    /// sanitized by `sanitize`.
+    ///
+    /// A directory or file name which would be empty, `.` or `..` (e.g. when `PatientID` is `..`)
+    /// has its placeholders replaced by their names instead, like how the default `px-push`
+    /// format uses the name of a missing tag.
+    pub fn render<'a>(
+        &self,
+        value_of: impl Fn(Placeholder) -> &'a str,
+        sanitize: impl Fn(&str) -> String,
+    ) -> String {
+        split_components(&self.0)
+            .into_iter()
+            .map(|component| {
+                let rendered: String = component
+                    .iter()
+                    .map(|segment| match segment {
+                        Segment::Literal(s) => s.to_string(),
+                        Segment::Placeholder(p) => sanitize(value_of(*p)),
+                    })
+                    .collect();
+                if is_empty_component(&rendered) {
+                    component
+                        .iter()
+                        .map(|segment| match segment {
+                            Segment::Literal(s) => s.as_str(),
+                            Segment::Placeholder(p) => p.name(),
+                        })
+                        .collect()
+                } else {
+                    rendered
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Split the segments of a template into the segments of each directory and file name.
+fn split_components(segments: &[Segment]) -> Vec<Vec<Segment>> {
+    let mut components = vec![Vec::new()];
+    for segment in segments {
+        match segment {
+            Segment::Literal(s) => {
+                for (i, part) in s.split('/').enumerate() {
+                    if i > 0 {
+                        components.push(Vec::new());
+                    }
+                    if !part.is_empty() {
+                        let last = components.last_mut().unwrap();
+                        last.push(Segment::Literal(part.to_string()));
+                    }
+                }
+            }
+            Segment::Placeholder(_) => components.last_mut().unwrap().push(segment.clone()),
+        }
+    }
+    components
+}
+
+/// Whether a directory or file name is not allowed in a path.
+pub(crate) fn is_empty_component(component: &str) -> bool {
+    matches!(component, "" | "." | "..")
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = PathTemplateError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut segments = Vec::new();
+        let mut rest = value.as_str();
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                segments.push(Segment::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..].find('}').ok_or(PathTemplateError::Unclosed)? + start;
+            let name = &rest[start + 1..end];
+            let placeholder = Placeholder::from_name(name)
+                .ok_or_else(|| PathTemplateError::UnknownPlaceholder(name.to_string()))?;
+            segments.push(Segment::Placeholder(placeholder));
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            segments.push(Segment::Literal(rest.to_string()));
+        }
+        validate(&segments)?;
+        Ok(Self(segments))
+    }
+}
+
+fn validate(segments: &[Segment]) -> Result<(), PathTemplateError> {
+    let literals = || {
+        segments.iter().filter_map(|s| match s {
+            Segment::Literal(s) => Some(s.as_str()),
+            _ => None,
+        })
+    };
+    let starts_with_slash =
+        matches!(segments.first(), Some(Segment::Literal(s)) if s.starts_with('/'));
+    if starts_with_slash || literals().any(|s| s.contains("..")) {
+        return Err(PathTemplateError::Escapes);
+    }
+    let only_literal_is_empty = |component: &Vec<Segment>| {
+        let literal: Option<String> = component
+            .iter()
+            .map(|segment| match segment {
+                Segment::Literal(s) => Some(s.as_str()),
+                Segment::Placeholder(_) => None,
+            })
+            .collect();
+        literal.is_some_and(|s| is_empty_component(&s))
+    };
+    if split_components(segments).iter().any(only_literal_is_empty) {
+        return Err(PathTemplateError::EmptyComponent);
+    }
+    let last_slash = segments
+        .iter()
+        .rposition(|s| matches!(s, Segment::Literal(s) if s.contains('/')))
+        .unwrap_or(0);
+    let (dir, fname) = segments.split_at(last_slash);
+    if !fname.contains(&Segment::Placeholder(Placeholder::SOPInstanceUID)) {
+        return Err(PathTemplateError::NotInstanceUnique);
+    }
+    if !dir.contains(&Segment::Placeholder(Placeholder::SeriesInstanceUID)) {
+        return Err(PathTemplateError::NotSeriesUnique);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sanitize::sanitize_path;
+    use rstest::*;
+
+    #[rstest]
+    #[case(
+        "{PatientID}/{SeriesInstanceUID}/{SOPInstanceUID}.dcm",
+        "P_1/1.2.3/4.5.6.dcm"
+    )]
+    #[case(
+        "{SeriesInstanceUID}/{InstanceNumber}-{SOPInstanceUID}",
+        "1.2.3/7-4.5.6"
+    )]
+    #[case(
+        "{StudyTime}/{SeriesInstanceUID}/{SeriesTime}-{SOPInstanceUID}",
+        "143015.5/1.2.3/-4.5.6"
+    )]
+    fn test_render(#[case] template: &str, #[case] expected: &str) {
+        let template = PathTemplate::try_from(template.to_string()).unwrap();
+        let actual = template.render(
+            |p| match p {
+                Placeholder::PatientID => "P 1",
+                Placeholder::SeriesInstanceUID => "1.2.3",
+                Placeholder::SOPInstanceUID => "4.5.6",
+                Placeholder::InstanceNumber => "7",
+                Placeholder::StudyTime => "143015.5",
+                _ => "",
+            },
+            |s: &str| sanitize_path(s),
+        );
+        assert_eq!(actual, expected)
+    }
+
+    #[rstest]
+    #[case("..", "", "PatientIDPatientName/1.2.3/4.5.6.dcm")]
+    #[case(".", ".", "PatientIDPatientName/1.2.3/4.5.6.dcm")]
+    #[case("", "", "PatientIDPatientName/1.2.3/4.5.6.dcm")]
+    #[case("", "Doe", "Doe/1.2.3/4.5.6.dcm")]
+    fn test_render_empty_component(
+        #[case] patient_id: &str,
+        #[case] patient_name: &str,
+        #[case] expected: &str,
+    ) {
+        let template = "{PatientID}{PatientName}/{SeriesInstanceUID}/{SOPInstanceUID}.dcm";
+        let template = PathTemplate::try_from(template.to_string()).unwrap();
+        let actual = template.render(
+            |p| match p {
+                Placeholder::PatientID => patient_id,
+                Placeholder::PatientName => patient_name,
+                Placeholder::SeriesInstanceUID => "1.2.3",
+                Placeholder::SOPInstanceUID => "4.5.6",
+                _ => "",
+            },
+            |s: &str| sanitize_path(s),
+        );
+        assert_eq!(actual, expected)
+    }
+
+    #[rstest]
+    #[case("{SeriesInstanceUID}/{Nope}/{SOPInstanceUID}", PathTemplateError::UnknownPlaceholder("Nope".to_string()))]
+    #[case("{SeriesInstanceUID}/{SOPInstanceUID", PathTemplateError::Unclosed)]
+    #[case("/{SeriesInstanceUID}/{SOPInstanceUID}", PathTemplateError::Escapes)]
+    #[case("../{SeriesInstanceUID}/{SOPInstanceUID}", PathTemplateError::Escapes)]
+    #[case(
+        "{SeriesInstanceUID}//{SOPInstanceUID}",
+        PathTemplateError::EmptyComponent
+    )]
+    #[case(
+        "./{SeriesInstanceUID}/{SOPInstanceUID}",
+        PathTemplateError::EmptyComponent
+    )]
+    #[case(
+        "{SeriesInstanceUID}/{InstanceNumber}.dcm",
+        PathTemplateError::NotInstanceUnique
+    )]
+    #[case(
+        "{SOPInstanceUID}/{SeriesInstanceUID}",
+        PathTemplateError::NotInstanceUnique
+    )]
+    #[case("{PatientID}/{SOPInstanceUID}.dcm", PathTemplateError::NotSeriesUnique)]
+    fn test_invalid(#[case] template: &str, #[case] expected: PathTemplateError) {
+        assert_eq!(PathTemplate::try_from(template.to_string()), Err(expected))
+    }
+}
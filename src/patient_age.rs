@@ -1,9 +1,14 @@
 /// Parse DICOM PatientAge to number of days.
 ///
+/// The value must be a number followed by one of the units `D`, `W`, `M` or `Y`.
+///
 /// https://github.com/FNNDSC/pypx/blob/7b83154d7c6d631d81eac8c9c4a2fc164ccc2ebc/pypx/register.py#L459-L465
 pub(crate) fn parse_age(age: &str) -> Option<i32> {
     for (suffix, coef) in &MULTIPLIERS {
         if let Some(left) = age.strip_suffix(suffix) {
+            if left.is_empty() || !left.bytes().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
             return left
                 .parse::<f32>()
                 .map(|num| (num * coef).round() as i32)
@@ -25,7 +30,23 @@ mod tests {
     #[case("020D", 20)]
     #[case("2W", 14)]
     #[case("5M", 152)]
+    #[case("090Y", 32872)]
+    #[case("018M", 548)]
+    #[case("006W", 42)]
+    #[case("045D", 45)]
     fn test_parse_age(#[case] age: &str, #[case] expected: i32) {
         assert_eq!(parse_age(age).unwrap(), expected)
     }
+
+    #[rstest]
+    #[case("")]
+    #[case("030")]
+    #[case("Y")]
+    #[case("abcY")]
+    #[case("-05Y")]
+    #[case("NaND")]
+    #[case("30X")]
+    fn test_parse_bad_age(#[case] age: &str) {
+        assert_eq!(parse_age(age), None)
+    }
 }
@@ -0,0 +1,102 @@
+//! Redaction of protected health information (PHI) from logs.
+//!
+//! When enabled by `OXIDICOM_LOG_REDACT`, values which identify a patient are replaced by a
+//! short hash, so that log messages about the same patient or file can still be correlated.
+//! Non-identifying values such as `SeriesInstanceUID` and `pacs_name` are logged as-is.
+use dicom::core::Tag;
+use dicom::dictionary_std::tags;
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static LOG_REDACT: AtomicBool = AtomicBool::new(false);
+
+/// Tags whose values are redacted.
+const PHI_TAGS: [Tag; 5] = [
+    tags::PATIENT_ID,
+    tags::PATIENT_NAME,
+    tags::PATIENT_BIRTH_DATE,
+    tags::PATIENT_AGE,
+    tags::ACCESSION_NUMBER,
+];
+
+/// Enable or disable redaction of logs.
+pub(crate) fn set_log_redact(enabled: bool) {
+    LOG_REDACT.store(enabled, Ordering::Relaxed);
+}
+
+fn is_enabled() -> bool {
+    LOG_REDACT.load(Ordering::Relaxed)
+}
+
+/// Redact a value to be logged, e.g. a path which contains a patient's name.
+pub(crate) fn redacted(value: &str) -> Cow<'_, str> {
+    if is_enabled() {
+        Cow::Owned(hashed(value))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Redact the value of a tag to be logged, if it is PHI.
+pub(crate) fn redacted_tag_value(tag: Tag, value: &str) -> Cow<'_, str> {
+    if PHI_TAGS.contains(&tag) {
+        redacted(value)
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Redact the paths quoted in an error message with backticks, e.g. from [fs_err].
+pub(crate) fn redacted_message(message: &str) -> Cow<'_, str> {
+    if !is_enabled() {
+        return Cow::Borrowed(message);
+    }
+    let mut parts = message.split('`');
+    let mut redacted = parts.next().unwrap_or_default().to_string();
+    for (i, part) in parts.enumerate() {
+        redacted.push('`');
+        if i % 2 == 0 {
+            redacted.push_str(&hashed(part));
+        } else {
+            redacted.push_str(part);
+        }
+    }
+    Cow::Owned(redacted)
+}
+
+fn hashed(value: &str) -> String {
+    let hash = format!("{:x}", seahash::hash(value.as_bytes()));
+    format!("redacted-{}", &hash[..7.min(hash.len())])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        set_log_redact(false);
+        assert_eq!(
+            redacted("SERVICES/PACS/BCH/DOE^JOHN"),
+            "SERVICES/PACS/BCH/DOE^JOHN"
+        );
+        assert_eq!(
+            redacted_message("failed to open file `/data/DOE^JOHN`"),
+            "failed to open file `/data/DOE^JOHN`"
+        );
+        set_log_redact(true);
+        let a = redacted("SERVICES/PACS/BCH/DOE^JOHN");
+        assert!(a.starts_with("redacted-"));
+        assert_ne!(a, redacted("SERVICES/PACS/BCH/DOE^JANE"));
+        assert_eq!(a, redacted("SERVICES/PACS/BCH/DOE^JOHN"));
+        assert_eq!(
+            redacted_tag_value(tags::PATIENT_ID, "1234"),
+            redacted("1234")
+        );
+        assert_eq!(redacted_tag_value(tags::STUDY_DATE, "2024"), "2024");
+        let message = redacted_message("failed to open file `/data/DOE^JOHN` (No such file)");
+        assert!(message.starts_with("failed to open file `redacted-"));
+        assert!(message.ends_with("` (No such file)"));
+        set_log_redact(false);
+    }
+}
@@ -7,18 +7,32 @@ use tokio::task::JoinHandle;
 
 use crate::batcher::Batcher;
 use crate::chrisdb_client::{CubePostgresClient, PacsFileDatabaseError};
-use crate::error::HandleLoopError;
+use crate::enums::StoredPacsFile;
+use crate::metrics::metrics;
 use crate::pacs_file::PacsFileRegistrationRequest;
+use crate::redact::{redacted, redacted_message};
+use crate::run_everything::RunStats;
+use crate::seen_index::SeenIndex;
+use crate::series_key_set::OXIDICOM_CUSTOM_PACS_NAME;
+use crate::storage::DicomStorage;
 
 /// Forward objects from `receiver` to the given `client`.
 ///
 /// - Received `Some`: add item to the batch. When batch is full, give everything to the `client`
 /// - Received `None`: flush current batch to the `client`
-pub async fn cube_pacsfile_registerer(
-    mut receiver: UnboundedReceiver<Option<PacsFileRegistrationRequest>>,
+///
+/// If `quarantine`, files which could not be registered are moved to the quarantine
+/// directory of their storage. DICOM files which were registered are added to `seen_index`.
+///
+/// Returns the numbers of files registered and of files which could not be registered.
+/// The other numbers are left as 0.
+pub(crate) async fn cube_pacsfile_registerer(
+    mut receiver: UnboundedReceiver<Option<StoredPacsFile>>,
     client: CubePostgresClient,
     batch_size: usize,
-) -> Result<(), HandleLoopError> {
+    quarantine: bool,
+    seen_index: Option<Arc<SeenIndex>>,
+) -> RunStats {
     // We have two loops:
     // 1. The receiver loop receives DICOM metadata from the receiver, and adds them to a batch.
     //    When the batch is full, we create a task to send the DICOM metadata to the database.
@@ -28,52 +42,49 @@ pub async fn cube_pacsfile_registerer(
     let receiver_loop = async {
         let mut batches = Batcher::new(batch_size);
         while let Some(event) = receiver.recv().await {
-            batches = handle_event(event, batches, &client, &tx).unwrap();
+            batches = handle_event(event, batches, &client, quarantine, &seen_index, &tx).unwrap();
         }
+        // last flush
+        handle_event(None, batches, &client, quarantine, &seen_index, &tx).unwrap();
         drop(tx);
-        flush_to_database(batches, client).await
     };
 
     // join tasks and take note of any errors.
-    let mut everything_ok = true;
+    let mut stats = RunStats::default();
     let joiner_loop = async {
         while let Some(task) = rx.recv().await {
-            if task.await.unwrap().is_err() {
-                everything_ok = false;
+            match task.await.unwrap() {
+                Ok(count) => stats.files_registered += count,
+                Err(count) => stats.errors += count,
             }
         }
     };
 
-    let (last_flush, _) = tokio::join!(receiver_loop, joiner_loop);
-    last_flush
-        .map_err(|_| HandleLoopError("Last flush of PACS files metadata to database failed."))?;
-    if everything_ok {
-        Ok(())
-    } else {
-        Err(HandleLoopError(
-            "There was an error registering PACS files metadata to the database.",
-        ))
-    }
+    tokio::join!(receiver_loop, joiner_loop);
+    stats
 }
 
-/// A tokio task of [CubePostgresClient::register]
-type RegistrationTask = JoinHandle<Result<(), PacsFileDatabaseError>>;
+/// A tokio task of [CubePostgresClient::register], which produces the number of files registered,
+/// or the number of files which could not be registered.
+type RegistrationTask = JoinHandle<Result<usize, usize>>;
 
 /// Receives `event` and calls [register_task] when needed, sending the task to `tx`.
 ///
 /// Returns the batch's next state.
 fn handle_event(
-    event: Option<PacsFileRegistrationRequest>,
-    prev: Batcher<PacsFileRegistrationRequest>,
+    event: Option<StoredPacsFile>,
+    prev: Batcher<StoredPacsFile>,
     client: &Arc<CubePostgresClient>,
+    quarantine: bool,
+    seen_index: &Option<Arc<SeenIndex>>,
     tx: &UnboundedSender<RegistrationTask>,
-) -> Result<Batcher<PacsFileRegistrationRequest>, SendError<RegistrationTask>> {
+) -> Result<Batcher<StoredPacsFile>, SendError<RegistrationTask>> {
     let (next, full_batch) = match event {
         None => take_batch(prev),
         Some(pacs_file) => prev.push(pacs_file),
     };
     if let Some(files) = full_batch {
-        let task = register_task(client, files);
+        let task = register_task(client, files, quarantine, seen_index.clone());
         tx.send(task)?;
     }
     Ok(next)
@@ -92,10 +103,12 @@ fn take_batch<T>(batches: Batcher<T>) -> (Batcher<T>, Option<Vec<T>>) {
     }
 }
 
-/// Wraps [CubePostgresClient::register] with [tokio::spawn] and [tracing].
+/// Wraps [register] with [tokio::spawn] and [tracing].
 fn register_task(
     client: &Arc<CubePostgresClient>,
-    files: Vec<PacsFileRegistrationRequest>,
+    files: Vec<StoredPacsFile>,
+    quarantine: bool,
+    seen_index: Option<Arc<SeenIndex>>,
 ) -> RegistrationTask {
     let client = Arc::clone(client);
     tokio::spawn(async move {
@@ -104,10 +117,13 @@ fn register_task(
         let cx = Context::current_with_span(span);
 
         let n_files = files.len();
-        let result = client.register(files).with_context(cx.clone()).await;
+        let result = register(&client, files, quarantine, seen_index.as_deref())
+            .with_context(cx.clone())
+            .await;
         match &result {
             Ok(_) => {
                 tracing::info!(task = "register", count = n_files);
+                metrics().files_registered.inc_by(n_files as u64);
                 cx.span().set_status(Status::Ok);
             }
             Err(e) => {
@@ -116,19 +132,75 @@ fn register_task(
             }
         }
         cx.span().end();
-        result
+        result.map(|_| n_files).map_err(|_| n_files)
     })
 }
 
-/// Consume the `batch` and give everything to [CubePostgresClient::register]
-async fn flush_to_database<C: AsRef<CubePostgresClient>>(
-    batch: Batcher<PacsFileRegistrationRequest>,
-    client: C,
+/// Call [CubePostgresClient::register]. If it fails and `quarantine` is true,
+/// the files are moved to the quarantine directory of their storage.
+/// If it succeeds, the DICOM files are added to `seen_index`.
+async fn register(
+    client: &CubePostgresClient,
+    files: Vec<StoredPacsFile>,
+    quarantine: bool,
+    seen_index: Option<&SeenIndex>,
 ) -> Result<(), PacsFileDatabaseError> {
-    let remaining = batch.into_inner();
-    if remaining.is_empty() {
-        Ok(())
-    } else {
-        client.as_ref().register(&remaining).await
+    let (requests, storages): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .map(|file| (file.request, file.storage))
+        .unzip();
+    let result = client.register(&requests).await;
+    if result.is_err() && quarantine {
+        quarantine_unregistered(client, requests, storages).await;
+    } else if let (Ok(()), Some(seen_index)) = (&result, seen_index) {
+        let dicom_paths: Vec<_> = requests
+            .iter()
+            .filter(|request| request.pacs_name.as_str() != OXIDICOM_CUSTOM_PACS_NAME)
+            .map(|request| request.path.as_str())
+            .collect();
+        if let Err(e) = seen_index.insert(&dicom_paths).await {
+            tracing::error!(event = "seen_index", error = e.to_string());
+        }
+    }
+    result
+}
+
+/// Move files which are not registered in CUBE's database to quarantine.
+///
+/// Files which are registered (e.g. by a previous push of the same series) are not moved.
+/// If the database cannot be queried, no files are moved.
+async fn quarantine_unregistered(
+    client: &CubePostgresClient,
+    requests: Vec<PacsFileRegistrationRequest>,
+    storages: Vec<Arc<dyn DicomStorage>>,
+) {
+    let unregistered: Vec<_> = match client.unregistered(&requests).await {
+        Ok(unregistered) => unregistered.into_iter().map(|f| f.path.clone()).collect(),
+        Err(e) => {
+            tracing::error!(event = "quarantine", error = e.to_string());
+            return;
+        }
+    };
+    let files: Vec<_> = requests
+        .into_iter()
+        .zip(storages)
+        .filter(|(request, _)| unregistered.contains(&request.path))
+        .collect();
+    let result = tokio::task::spawn_blocking(move || {
+        for (request, storage) in files {
+            match storage.quarantine(&request.path) {
+                Ok(location) => {
+                    tracing::error!(event = "quarantine", path = redacted(&location).as_ref())
+                }
+                Err(e) => tracing::error!(
+                    event = "quarantine",
+                    error = redacted_message(&e.to_string()).as_ref()
+                ),
+            }
+        }
+    })
+    .await;
+    if let Err(e) = result {
+        tracing::error!(event = "quarantine", error = e.to_string());
     }
 }
@@ -1,37 +1,70 @@
-use crate::enums::PendingRegistration;
+use crate::enums::{PendingRegistration, StorageTask, StoredPacsFile};
 use crate::error::HandleLoopError;
-use crate::pacs_file::PacsFileRegistrationRequest;
+use crate::manifest::PendingManifest;
+use crate::run_everything::RunStats;
 use crate::series_key_set::SeriesKeySet;
+use crate::storage::DicomStorage;
 use futures::StreamExt;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 use tokio::sync::mpsc::error::SendError;
 use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
 use tokio::task::JoinHandle;
+use ulid::Ulid;
 
 /// `registration_synchronizer` is intended as a way to synchronize requests before they are
 /// sent to [crate::registerer::cube_pacsfile_registerer]. It guarantees that the "flush" command
 /// can be invoked after all tasks for an association are complete.
+///
+/// Tasks are grouped by association as well as by series, so that a series which is pushed
+/// in more than one association at the same time is synchronized separately for each.
+///
+/// At the end of a series, its manifest is written listing the files which were stored.
+///
+/// The end of a series for which no tasks were received should not happen. It is logged
+/// as an error, and the "flush" command is sent anyway.
+///
+/// Returns the numbers of files written and of files which could not be written.
+/// The other numbers are left as 0.
 pub(crate) async fn registration_synchronizer(
-    mut receiver: UnboundedReceiver<(SeriesKeySet, PendingRegistration)>,
-    sender: UnboundedSender<Option<PacsFileRegistrationRequest>>,
-) -> Result<(), HandleLoopError> {
+    mut receiver: UnboundedReceiver<(Ulid, SeriesKeySet, PendingRegistration)>,
+    sender: UnboundedSender<Option<StoredPacsFile>>,
+) -> Result<RunStats, HandleLoopError> {
     let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
     let receiver_loop = async {
-        let mut inflight_series: HashMap<SeriesKeySet, Vec<_>> = Default::default();
+        let mut inflight_series: HashMap<(Ulid, SeriesKeySet), Vec<_>> = Default::default();
         let sender = Arc::new(sender);
-        while let Some((series, event)) = receiver.recv().await {
+        while let Some((ulid, series, event)) = receiver.recv().await {
+            let series = (ulid, series);
             match event {
-                PendingRegistration::Task(task) => {
-                    enqueue_registration_and_insert(series, task, &sender, &mut inflight_series)
-                }
-                PendingRegistration::End => {
-                    let tasks_for_series = inflight_series
-                        .remove(&series)
-                        .expect("No tasks were received for the series.");
+                PendingRegistration::Task(task, storage) => enqueue_registration_and_insert(
+                    series,
+                    task,
+                    storage,
+                    &sender,
+                    &mut inflight_series,
+                ),
+                PendingRegistration::End(manifest) => {
+                    let Some(tasks_for_series) = inflight_series.remove(&series) else {
+                        let series_of_association =
+                            inflight_series.keys().filter(|(u, _)| *u == ulid).count();
+                        tracing::error!(
+                            association_ulid = series.0.to_string(),
+                            pacs_name = series.1.pacs_name.as_str(),
+                            SeriesInstanceUID = series.1.SeriesInstanceUID.as_str(),
+                            series_of_association,
+                            inflight_series = inflight_series.len(),
+                            "End of a series for which no tasks were received."
+                        );
+                        // flush anyway, so that files which were already sent are not held back
+                        if sender.send(None).is_err() {
+                            tracing::error!("Registerer stopped before the end of a series.");
+                        }
+                        continue;
+                    };
                     let sender = Arc::clone(&sender);
                     let task = tokio::task::spawn(async move {
-                        wait_on_all_then_flush(tasks_for_series, &sender).await
+                        wait_on_all_then_flush(tasks_for_series, manifest, &sender).await
                     });
                     tx.send(task).unwrap()
                 }
@@ -40,17 +73,24 @@ pub(crate) async fn registration_synchronizer(
         drop(tx);
     };
     let mut everything_ok = true;
+    let mut stats = RunStats::default();
     let joiner_loop = async {
         while let Some(handle) = rx.recv().await {
-            if let Err(e) = handle.await.unwrap() {
-                tracing::error!("{}", e.to_string());
-                everything_ok = false;
+            match handle.await.unwrap() {
+                Ok(series_stats) => {
+                    stats.files_written += series_stats.files_written;
+                    stats.errors += series_stats.errors;
+                }
+                Err(e) => {
+                    tracing::error!("{}", e.to_string());
+                    everything_ok = false;
+                }
             }
         }
     };
     tokio::join!(receiver_loop, joiner_loop);
     if everything_ok {
-        Ok(())
+        Ok(stats)
     } else {
         Err(HandleLoopError(
             "There was an error in registration_synchronizer",
@@ -58,25 +98,42 @@ pub(crate) async fn registration_synchronizer(
     }
 }
 
-/// Create a task which joins the given `task`. If the given `task` is [Ok], send the
-/// [PacsFileRegistrationRequest] to `sender`.
+/// What a [RegisterTask] did with a file.
+enum Registration {
+    /// The file was stored at this path, and sent to be registered
+    Sent(String),
+    /// The file was not stored on purpose
+    Skipped,
+    /// The file could not be stored
+    Failed,
+}
+
+/// A task which sends a stored file to be registered.
+type RegisterTask = JoinHandle<Result<Registration, SendError<Option<StoredPacsFile>>>>;
+
+/// Create a task which joins the given `task`. If the given `task` produces a
+/// [crate::pacs_file::PacsFileRegistrationRequest], send it to `sender` along with the `storage`
+/// it is in.
 ///
 /// Insert the created task into `inflight_series`.
 fn enqueue_registration_and_insert(
-    series: SeriesKeySet,
-    task: JoinHandle<Result<PacsFileRegistrationRequest, ()>>,
-    sender: &Arc<UnboundedSender<Option<PacsFileRegistrationRequest>>>,
-    inflight_series: &mut HashMap<
-        SeriesKeySet,
-        Vec<JoinHandle<Result<(), SendError<Option<PacsFileRegistrationRequest>>>>>,
-    >,
+    series: (Ulid, SeriesKeySet),
+    task: StorageTask,
+    storage: Arc<dyn DicomStorage>,
+    sender: &Arc<UnboundedSender<Option<StoredPacsFile>>>,
+    inflight_series: &mut HashMap<(Ulid, SeriesKeySet), Vec<RegisterTask>>,
 ) {
-    let sender = Arc::clone(&sender);
+    let sender = Arc::clone(sender);
     let register_task = tokio::task::spawn(async move {
-        if let Ok(pacs_file) = task.await.unwrap() {
-            sender.send(Some(pacs_file))
-        } else {
-            Ok(())
+        match task.await.unwrap() {
+            Ok(Some(request)) => {
+                let path = request.path.clone();
+                sender
+                    .send(Some(StoredPacsFile { request, storage }))
+                    .map(|_| Registration::Sent(path))
+            }
+            Ok(None) => Ok(Registration::Skipped),
+            Err(()) => Ok(Registration::Failed),
         }
     });
     if let Some(v) = inflight_series.get_mut(&series) {
@@ -86,19 +143,162 @@ fn enqueue_registration_and_insert(
     }
 }
 
-/// Wait on all the tasks, then send [None] to `sender`.
+/// Wait on all the tasks, then write the `manifest` of the files they stored, then send [None]
+/// to `sender`. Returns the numbers of files written and of files which could not be written.
 async fn wait_on_all_then_flush<E: ToString, P>(
-    tasks: Vec<JoinHandle<Result<(), E>>>,
+    tasks: Vec<JoinHandle<Result<Registration, E>>>,
+    manifest: Option<PendingManifest>,
     sender: &UnboundedSender<Option<P>>,
-) -> Result<(), SendError<Option<P>>> {
-    futures::stream::iter(tasks)
+) -> Result<RunStats, SendError<Option<P>>> {
+    let mut stats = RunStats::default();
+    let mut stored = HashSet::with_capacity(tasks.len());
+    let mut results = futures::stream::iter(tasks)
         .map(|handle| async { handle.await.unwrap() })
-        .buffer_unordered(usize::MAX)
-        .for_each(|result| async {
-            if let Err(error) = result {
-                tracing::error!("{}", error.to_string())
+        .buffer_unordered(usize::MAX);
+    while let Some(result) = results.next().await {
+        match result {
+            Ok(Registration::Sent(path)) => {
+                stats.files_written += 1;
+                stored.insert(path);
             }
-        })
-        .await;
-    sender.send(None)
+            Ok(Registration::Skipped) => (),
+            Ok(Registration::Failed) => stats.errors += 1,
+            Err(error) => tracing::error!("{}", error.to_string()),
+        }
+    }
+    if let Some(manifest) = manifest {
+        manifest.write(&stored).await;
+    }
+    sender.send(None).map(|_| stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dicomrs_settings::ClientAETitle;
+    use crate::manifest::{ManifestInstance, SeriesManifest};
+    use crate::pacs_file::tests::{example_dcm, example_options};
+    use crate::pacs_file::PacsFileRegistrationRequest;
+    use crate::storage::{Compression, FileModes, FilesystemStorage};
+    use crate::test_util::temp_dir;
+    use futures::FutureExt;
+
+    #[tokio::test]
+    async fn test_same_series_in_overlapping_associations() {
+        let (request, _) = PacsFileRegistrationRequest::new(
+            ClientAETitle::from_static("ORTHANC"),
+            &example_dcm("Chest"),
+            &example_options(),
+        )
+        .unwrap();
+        let series = SeriesKeySet::from(request.clone());
+        let task = || {
+            let request = request.clone();
+            let storage = FilesystemStorage::new(
+                "/nonexistent".into(),
+                Compression::None,
+                FileModes::default(),
+            );
+            PendingRegistration::Task(
+                tokio::task::spawn(async move { Ok(Some(request)) }),
+                Arc::new(storage),
+            )
+        };
+        let (a, b) = (Ulid::new(), Ulid::new());
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tx_register, mut rx_register) = tokio::sync::mpsc::unbounded_channel();
+        tx.send((a, series.clone(), task())).unwrap();
+        tx.send((b, series.clone(), task())).unwrap();
+        tx.send((a, series.clone(), PendingRegistration::End(None)))
+            .unwrap();
+        tx.send((b, series.clone(), PendingRegistration::End(None)))
+            .unwrap();
+        drop(tx);
+        registration_synchronizer(rx, tx_register).await.unwrap();
+        let mut received = Vec::new();
+        while let Some(message) = rx_register.recv().await {
+            received.push(message.is_some());
+        }
+        assert_eq!(received.iter().filter(|is_some| **is_some).count(), 2);
+        assert_eq!(received.iter().filter(|is_some| !**is_some).count(), 2);
+        assert!(!received.last().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_end_without_tasks() {
+        let (request, _) = PacsFileRegistrationRequest::new(
+            ClientAETitle::from_static("ORTHANC"),
+            &example_dcm("Chest"),
+            &example_options(),
+        )
+        .unwrap();
+        let series = SeriesKeySet::from(request);
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tx_register, mut rx_register) = tokio::sync::mpsc::unbounded_channel();
+        tx.send((Ulid::new(), series, PendingRegistration::End(None)))
+            .unwrap();
+        drop(tx);
+        registration_synchronizer(rx, tx_register).await.unwrap();
+        assert!(rx_register.recv().await.unwrap().is_none());
+        assert!(rx_register.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_manifest_lists_stored_instances() {
+        let root = temp_dir();
+        let storage: Arc<dyn DicomStorage> = Arc::new(FilesystemStorage::new(
+            root.clone(),
+            Compression::None,
+            FileModes::default(),
+        ));
+        let (request, _) = PacsFileRegistrationRequest::new(
+            ClientAETitle::from_static("ORTHANC"),
+            &example_dcm("Chest"),
+            &example_options(),
+        )
+        .unwrap();
+        let series = SeriesKeySet::from(request.clone());
+        let ulid = Ulid::new();
+        let instance = |sop_instance_uid: &str, path: &str| ManifestInstance {
+            SOPInstanceUID: sop_instance_uid.to_string(),
+            path: path.to_string(),
+        };
+        let manifest = SeriesManifest {
+            association_ulid: ulid.to_string(),
+            pacs_name: "ORTHANC".to_string(),
+            label: None,
+            StudyInstanceUID: request.StudyInstanceUID.clone(),
+            SeriesInstanceUID: request.SeriesInstanceUID.clone(),
+            ndicom: 2,
+            NumberOfSeriesRelatedInstances: None,
+            instances: vec![
+                instance("1.2.3", &request.path),
+                instance("4.5.6", "failed.dcm"),
+            ],
+        };
+        let manifest = PendingManifest {
+            path: "manifest.json".to_string(),
+            manifest,
+            pacs_info: futures::future::ready(None).boxed().shared(),
+            storage: Arc::clone(&storage),
+        };
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (tx_register, mut rx_register) = tokio::sync::mpsc::unbounded_channel();
+        for result in [Ok(Some(request.clone())), Err(())] {
+            let task = tokio::task::spawn(async move { result });
+            let task = PendingRegistration::Task(task, Arc::clone(&storage));
+            tx.send((ulid, series.clone(), task)).unwrap();
+        }
+        tx.send((ulid, series, PendingRegistration::End(Some(manifest))))
+            .unwrap();
+        drop(tx);
+        let stats = registration_synchronizer(rx, tx_register).await.unwrap();
+        assert_eq!((stats.files_written, stats.errors), (1, 1));
+        assert!(rx_register.recv().await.unwrap().is_some());
+        assert!(rx_register.recv().await.unwrap().is_none());
+        let written: serde_json::Value =
+            serde_json::from_slice(&fs_err::read(root.join("manifest.json")).unwrap()).unwrap();
+        assert_eq!(written["ndicom"], 1);
+        assert_eq!(written["instances"][0]["path"], request.path.as_str());
+    }
 }
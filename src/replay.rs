@@ -0,0 +1,214 @@
+//! Registration of DICOM files which are already in storage, e.g. after _CUBE_'s database was reset.
+use crate::chrisdb_client::CubePostgresClient;
+use crate::dicomrs_settings::ClientAETitle;
+use crate::get_config;
+use crate::pacs_file::{PacsFileOptions, PacsFileRegistrationRequest};
+use crate::series_key_set::OXIDICOM_CUSTOM_PACS_NAME;
+use crate::settings::OxidicomEnvOptions;
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::dictionary_std::tags;
+use dicom::object::file::ReadPreamble;
+use dicom::object::OpenFileOptions;
+use flate2::read::GzDecoder;
+use sqlx::postgres::PgPoolOptions;
+use std::io::{BufReader, Read};
+use std::sync::Arc;
+
+/// Register every DICOM file under the storage roots from the configuration in environment
+/// variables which is not already registered. No TCP ports are bound.
+///
+/// Files are registered with the paths they are found at and the metadata read from them.
+/// "Oxidicom Custom Metadata" files cannot be recreated this way and are skipped.
+pub async fn replay_from_env() -> anyhow::Result<()> {
+    let options: OxidicomEnvOptions = get_config().extract()?;
+    if options.s3.is_some() {
+        anyhow::bail!("Replay is not supported with object storage");
+    }
+    let pacs_file_options = Arc::new(PacsFileOptions {
+        path_template: None,
+        extra_date_formats: options.extra_date_formats,
+        allow_missing_tags: options.allow_missing_tags,
+        fill_from_pacs: false,
+        max_path_component_length: options.max_path_component_length,
+        compression: options.compress,
+        series_hash: options.series_hash,
+        sanitize_hash: options.sanitize_hash,
+    });
+    let pool = PgPoolOptions::new()
+        .max_connections(options.db.pool.get())
+        .connect(&options.db.connection)
+        .await?;
+    let client = CubePostgresClient::new(pool, None);
+    let mut roots: Vec<_> = std::iter::once(options.files_root)
+        .chain(options.listeners.into_iter().map(|l| l.files_root))
+        .chain(options.called_aet_files_root.into_values())
+        .collect();
+    roots.sort();
+    roots.dedup();
+    let (mut registered, mut failed) = (0, 0);
+    for root in roots {
+        let paths = {
+            let root = root.clone();
+            tokio::task::spawn_blocking(move || find_dicom_files(&root)).await??
+        };
+        tracing::info!(root = root.as_str(), files = paths.len(), "Replaying.");
+        for batch in paths.chunks(options.db.batch_size.get()) {
+            let (requests, unreadable) = {
+                let root = root.clone();
+                let batch = batch.to_vec();
+                let pacs_file_options = Arc::clone(&pacs_file_options);
+                tokio::task::spawn_blocking(move || read_requests(&root, batch, &pacs_file_options))
+                    .await?
+            };
+            failed += unreadable;
+            let unregistered: Vec<_> = client
+                .unregistered(&requests)
+                .await?
+                .into_iter()
+                .cloned()
+                .collect();
+            if unregistered.is_empty() {
+                continue;
+            }
+            match client.register(&unregistered).await {
+                Ok(()) => registered += unregistered.len(),
+                Err(e) => {
+                    tracing::error!(message = e.to_string());
+                    failed += unregistered.len();
+                }
+            }
+        }
+    }
+    tracing::info!(registered, failed, "Replay finished.");
+    if failed > 0 {
+        anyhow::bail!("{failed} files could not be registered");
+    }
+    Ok(())
+}
+
+/// Find the paths, relative to `root`, of DICOM files under `SERVICES/PACS` which were
+/// received by _oxidicom_, i.e. excluding "Oxidicom Custom Metadata" and temporary files.
+fn find_dicom_files(root: &Utf8Path) -> std::io::Result<Vec<Utf8PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = Vec::new();
+    let services_pacs = root.join("SERVICES/PACS");
+    if !services_pacs.is_dir() {
+        return Ok(files);
+    }
+    for entry in fs_err::read_dir(&services_pacs)? {
+        let entry = entry?;
+        if entry.file_name() != OXIDICOM_CUSTOM_PACS_NAME {
+            dirs.push(entry.path());
+        }
+    }
+    while let Some(dir) = dirs.pop() {
+        for entry in fs_err::read_dir(&dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if entry.file_type()?.is_dir() {
+                dirs.push(path);
+            } else if !name.starts_with('.')
+                && (name.ends_with(".dcm") || name.ends_with(".dcm.gz"))
+            {
+                let path = Utf8PathBuf::from_path_buf(path)
+                    .map_err(|p| std::io::Error::other(format!("Not UTF-8: {p:?}")))?;
+                files.push(path.strip_prefix(root).unwrap().to_path_buf());
+            }
+        }
+    }
+    files.sort_unstable();
+    Ok(files)
+}
+
+/// Read the metadata of DICOM files for registration. Files which cannot be read are logged,
+/// and their number is returned.
+fn read_requests(
+    root: &Utf8Path,
+    paths: Vec<Utf8PathBuf>,
+    options: &PacsFileOptions,
+) -> (Vec<PacsFileRegistrationRequest>, usize) {
+    let mut requests = Vec::with_capacity(paths.len());
+    let mut failed = 0;
+    for path in paths {
+        match read_request(root, &path, options) {
+            Ok(request) => requests.push(request),
+            Err(e) => {
+                tracing::error!(path = path.as_str(), message = format!("{e:#}"));
+                failed += 1;
+            }
+        }
+    }
+    (requests, failed)
+}
+
+/// Read the metadata of the DICOM file at `path` relative to `root` for registration.
+///
+/// The PACS name is the directory under `SERVICES/PACS`, and the path is kept as-is.
+fn read_request(
+    root: &Utf8Path,
+    path: &Utf8Path,
+    options: &PacsFileOptions,
+) -> anyhow::Result<PacsFileRegistrationRequest> {
+    let pacs_name = path
+        .strip_prefix("SERVICES/PACS")?
+        .components()
+        .next()
+        .map(|c| ClientAETitle::from(c.as_str()))
+        .ok_or_else(|| anyhow::anyhow!("No PACS name"))?;
+    let file = BufReader::new(fs_err::File::open(root.join(path))?);
+    let reader: Box<dyn Read> = if path.extension() == Some("gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let dcm = OpenFileOptions::new()
+        .read_preamble(ReadPreamble::Always)
+        .read_until(tags::PIXEL_DATA)
+        .from_reader(reader)?;
+    let (mut request, _) = PacsFileRegistrationRequest::new(pacs_name, &dcm, options)?;
+    request.path = path.to_string();
+    Ok(request)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pacs_file::tests::{example_dcm, example_options};
+    use crate::pacs_file::PacsFileRegistration;
+    use crate::storage::{Compression, DicomStorage, FileModes, FilesystemStorage};
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn test_find_and_read_dicom_files() {
+        let root = temp_dir();
+        let options = PacsFileOptions {
+            compression: Compression::Gzip,
+            ..example_options()
+        };
+        let storage = FilesystemStorage::new(root.clone(), Compression::Gzip, FileModes::default());
+        let (pacs_file, _) =
+            PacsFileRegistration::new("ORTHANC".into(), example_dcm("Chest"), &options).unwrap();
+        storage.write_dicom(&pacs_file).unwrap();
+        let custom =
+            format!("SERVICES/PACS/{OXIDICOM_CUSTOM_PACS_NAME}/a/OxidicomAttemptedPushCount=1");
+        storage.write_file(&custom, b"").unwrap();
+        storage
+            .write_file("SERVICES/PACS/ORTHANC/.1.dcm.partial", b"")
+            .unwrap();
+        storage
+            .write_file("quarantine/SERVICES/PACS/ORTHANC/2.dcm", b"")
+            .unwrap();
+
+        let files = find_dicom_files(&root).unwrap();
+        let expected = pacs_file.request.path.clone();
+        assert!(expected.ends_with(".dcm.gz"));
+        assert_eq!(files, vec![Utf8PathBuf::from(&expected)]);
+        let request = read_request(&root, &files[0], &options).unwrap();
+        assert_eq!(request.path, expected);
+        assert_eq!(request.pacs_name.as_str(), "ORTHANC");
+        assert_eq!(request.SeriesInstanceUID, "1.2.3.4");
+    }
+}
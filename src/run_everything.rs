@@ -1,70 +1,495 @@
-use crate::association_series_state_loop::association_series_state_loop;
+use crate::association_series_state_loop::{
+    association_series_state_loop, FindScu, HandlerOptions,
+};
 use crate::chrisdb_client::CubePostgresClient;
+use crate::dicomrs_settings::{CalledAeTitlePolicy, OurAETitle};
+use crate::error::RunError;
+use crate::findscu::FindScuOptions;
+use crate::findscu_cache::FindScuCache;
+use crate::free_space::FreeSpaceGuard;
 use crate::get_config;
-use sqlx::postgres::PgPoolOptions;
-use std::net::{Ipv4Addr, SocketAddrV4};
-use tokio::sync::mpsc;
+use crate::health::health_server;
+use sqlx::postgres::{PgPool, PgPoolOptions};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, Semaphore};
 
-use crate::listener_tcp_loop::dicom_listener_tcp_loop;
+use crate::listener_tcp_loop::{dicom_listener_tcp_loop, ConnectionLimits};
+use crate::metrics::metrics_server;
+use crate::pacs_file::PacsFileOptions;
+use crate::pacs_limiter::{FindScuLimiter, PacsConcurrencyLimiter};
+use crate::redact::set_log_redact;
 use crate::registerer::cube_pacsfile_registerer;
 use crate::registration_synchronizer::registration_synchronizer;
-use crate::settings::OxidicomEnvOptions;
-use futures::FutureExt;
+use crate::s3::S3Storage;
+use crate::scp::ScpParameters;
+use crate::seen_index::SeenIndex;
+use crate::settings::{DatabaseOptions, ListenerOptions, OxidicomEnvOptions};
+use crate::spool::SPOOL_DIR_NAME;
+use crate::storage::{DicomStorage, FileModes, FilesystemStorage, StoreOptions};
+use dicom::ul::ServerAssociationOptions;
+use futures::{future, FutureExt, TryFutureExt};
+
+/// Summary of what [run_everything_from_env] did.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RunStats {
+    /// Number of associations started
+    pub associations: usize,
+    /// Number of DICOM instances accepted, not counting duplicates nor rejected ones
+    pub instances: usize,
+    /// Number of series received, counted once for every association they were received in
+    pub series: usize,
+    /// Number of files written to storage, including "Oxidicom Custom Metadata" files
+    /// and existing files which were kept
+    pub files_written: usize,
+    /// Number of files registered to _CUBE_, including "Oxidicom Custom Metadata" files
+    pub files_registered: usize,
+    /// Number of files which could not be written or registered
+    pub errors: usize,
+}
 
 /// Calls [run_everything] using configuration from environment variables.
 ///
 /// Function parameters are prioritized over environment variable values.
 ///
 /// `finite_connections`: shut down the server after the given number of DICOM associations.
-pub async fn run_everything_from_env(finite_connections: Option<usize>) -> anyhow::Result<()> {
+///
+/// Files which could not be written or registered do not make it fail,
+/// they are counted in [RunStats::errors].
+pub async fn run_everything_from_env(
+    finite_connections: Option<usize>,
+) -> Result<RunStats, RunError> {
     let config = get_config();
-    let settings = config.extract()?;
+    let settings = config.extract().map_err(Box::new)?;
     run_everything(settings, finite_connections).await
 }
 
 /// Runs everything in parallel:
 ///
-/// 1. A TCP server loop to listen for incoming DICOM objects
+/// 1. A TCP server loop for each configured listener to listen for incoming DICOM objects
 /// 2. A file storage handler which writes DICOM files to disk
 /// 3. A database connection pool which registers written files
+/// 4. (optional) An HTTP server for Prometheus metrics
+/// 5. (optional) An HTTP server for health checks, which is ready once the
+///    database is connected and the TCP server is listening
+///
+/// On SIGINT or SIGTERM, the TCP server stops accepting new connections. The function
+/// returns after in-flight associations are done and their files are registered.
 async fn run_everything(
     OxidicomEnvOptions {
         db,
+        db_connect_retries,
+        db_connect_interval,
+        db_connect_max_interval,
         files_root,
+        s3,
+        path_template,
+        extra_date_formats,
+        allow_missing_tags,
+        fill_from_pacs,
+        max_path_component_length,
+        compress,
+        series_hash,
+        sanitize_hash,
+        file_mode,
+        dir_mode,
+        store_raw,
+        stream_to_disk,
+        dry_run,
+        log_redact,
+        quarantine,
+        seen_index,
+        seen_index_retention,
+        write_manifest,
+        label,
         scp,
+        scp_check_called_aet,
         scp_max_pdu_length,
+        instance_buffer_capacity,
+        max_instance_bytes,
+        max_series_per_association,
+        transfer_syntax_check,
+        unreadable_instances,
+        implementation_class_uid,
+        on_existing,
+        verify_write,
+        association_queue_size,
+        scp_timeout,
+        max_association_duration,
+        association_idle_timeout,
         pacs_address,
+        pacs_name,
+        disable_findscu,
+        findscu_timeout,
+        findscu_retries,
+        findscu_delay,
+        findscu_jitter,
+        findscu_concurrency,
+        findscu_cache_ttl,
+        findscu_cache_unknown_ttl,
+        findscu_cache_size,
+        allowed_ae_titles,
+        pacs_concurrency,
+        pacs_concurrency_default,
+        min_free_bytes,
         listener_threads,
+        max_inflight_associations,
+        listener_address,
         listener_port,
+        listeners,
+        called_aet_files_root,
+        metrics_address,
+        health_address,
     }: OxidicomEnvOptions,
     finite_connections: Option<usize>,
-) -> anyhow::Result<()> {
-    let db_pool = PgPoolOptions::new()
-        .max_connections(db.pool.get())
-        .connect(&db.connection)
-        .await?;
+) -> Result<RunStats, RunError> {
+    if let Some(idle_timeout) = association_idle_timeout {
+        // otherwise, associations which are still receiving data could be finished
+        if scp_timeout.is_none_or(|scp_timeout| idle_timeout < scp_timeout) {
+            let message =
+                "OXIDICOM_ASSOCIATION_IDLE_TIMEOUT must not be shorter than OXIDICOM_SCP_TIMEOUT";
+            return Err(Box::new(figment::Error::from(message.to_string())).into());
+        }
+    }
+    set_log_redact(log_redact);
+    let metrics_handle = metrics_address.map(|address| tokio::spawn(metrics_server(address)));
+    let ready = Arc::new(AtomicBool::new(false));
+    let health_handle =
+        health_address.map(|address| tokio::spawn(health_server(address, Arc::clone(&ready))));
+    let db_pool = connect_database(
+        &db,
+        db_connect_retries,
+        db_connect_interval,
+        db_connect_max_interval,
+    )
+    .await
+    .map_err(RunError::Database)?;
     let cubedb_client = CubePostgresClient::new(db_pool, None);
+    let seen_index = if let Some(path) = seen_index {
+        let index = SeenIndex::open(&path, seen_index_retention)
+            .await
+            .map_err(RunError::SeenIndex)?;
+        Some(Arc::new(index))
+    } else {
+        None
+    };
 
-    let (tx_association, rx_association) = mpsc::unbounded_channel();
+    // The listener threads block when tx_association is full, which throttles receiving.
+    // The channels downstream of the state loop are unbounded, so the state loop never blocks
+    // on sending and is always able to drain rx_association.
+    let (tx_association, rx_association) = mpsc::channel(association_queue_size.get());
     let (tx_storetasks, rx_storetasks) = mpsc::unbounded_channel();
     let (tx_register, rx_register) = mpsc::unbounded_channel();
-    let listener_handle = tokio::task::spawn_blocking(move || {
-        dicom_listener_tcp_loop(
-            SocketAddrV4::new(Ipv4Addr::from(0), listener_port),
-            scp,
-            finite_connections,
-            listener_threads.get(),
-            scp_max_pdu_length,
-            tx_association,
-            pacs_address,
-        )
+    let pacs_file_options = Arc::new(PacsFileOptions {
+        path_template,
+        extra_date_formats,
+        allow_missing_tags,
+        fill_from_pacs,
+        max_path_component_length,
+        compression: compress,
+        series_hash,
+        sanitize_hash,
     });
+    let scp_aet = scp.aet.clone();
+    let policy = if scp_check_called_aet {
+        CalledAeTitlePolicy::AcceptCalledAeTitle
+    } else {
+        CalledAeTitlePolicy::AcceptAny
+    };
+    let options: ServerAssociationOptions<'static, CalledAeTitlePolicy> =
+        ServerAssociationOptions::from(scp).ae_access_control(policy);
+    let primary_listener = ListenerOptions {
+        port: listener_port,
+        aet: scp_aet,
+        files_root,
+    };
+    let listeners: Vec<_> = std::iter::once(primary_listener).chain(listeners).collect();
+    let listener_addresses: Vec<_> = listeners
+        .iter()
+        .map(|l| SocketAddr::new(listener_address, l.port))
+        .collect();
+    let pacs_concurrency = if pacs_concurrency.is_empty() && pacs_concurrency_default.is_none() {
+        None
+    } else {
+        Some(Arc::new(PacsConcurrencyLimiter::new(
+            pacs_concurrency,
+            pacs_concurrency_default,
+        )))
+    };
+    let s3_storage: Option<Arc<dyn DicomStorage>> = s3
+        .map(|s3| S3Storage::new(s3, compress))
+        .transpose()
+        .map_err(RunError::S3)?
+        .map(|s3| Arc::new(s3) as Arc<dyn DicomStorage>);
+    let file_modes = FileModes {
+        file: file_mode,
+        dir: dir_mode,
+    };
+    // with S3, all files are stored in the bucket
+    let called_aet_storage: HashMap<OurAETitle, Arc<dyn DicomStorage>> = if s3_storage.is_some() {
+        Default::default()
+    } else {
+        called_aet_files_root
+            .into_iter()
+            .map(|(aet, root)| {
+                let storage = FilesystemStorage::new(root, compress, file_modes);
+                (aet, Arc::new(storage) as Arc<dyn DicomStorage>)
+            })
+            .collect()
+    };
+    let inflight_limit = max_inflight_associations.map(|n| Arc::new(Semaphore::new(n.get())));
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_handle = tokio::spawn(shutdown_on_signal(
+        Arc::clone(&shutdown),
+        Arc::clone(&ready),
+        listener_addresses.clone(),
+    ));
+    let not_started = Arc::new(AtomicUsize::new(listeners.len()));
+    let mut listener_handles = Vec::with_capacity(listeners.len());
+    for ListenerOptions {
+        port,
+        aet,
+        files_root,
+    } in listeners
+    {
+        let spool_dir = if stream_to_disk {
+            let dir = files_root.join(SPOOL_DIR_NAME);
+            fs_err::create_dir_all(&dir).map_err(RunError::SpoolDir)?;
+            Some(dir)
+        } else {
+            None
+        };
+        let scp_params = ScpParameters {
+            options: options.clone().ae_title(aet.to_string()),
+            aet,
+            max_pdu_length: scp_max_pdu_length,
+            timeout: scp_timeout,
+            max_duration: max_association_duration,
+            pacs_addresses: pacs_address.clone(),
+            pacs_names: pacs_name.clone(),
+            allowed_ae_titles: allowed_ae_titles.clone(),
+            pacs_concurrency: pacs_concurrency.clone(),
+            store_raw,
+            spool_dir,
+            instance_buffer_capacity,
+            max_instance_bytes,
+            max_series: max_series_per_association,
+            transfer_syntax_check,
+            unreadable_instances,
+            implementation_class_uid: implementation_class_uid.clone(),
+            pacs_file_options: Arc::clone(&pacs_file_options),
+            free_space: min_free_bytes.map(|n| FreeSpaceGuard::new(files_root.clone(), n)),
+            called_aet_storage: called_aet_storage.clone(),
+            storage: s3_storage.clone().unwrap_or_else(|| {
+                Arc::new(FilesystemStorage::new(files_root, compress, file_modes))
+            }),
+        };
+        let tx_association = tx_association.clone();
+        let inflight_limit = inflight_limit.clone();
+        let ready = Arc::clone(&ready);
+        let not_started = Arc::clone(&not_started);
+        let on_start = move || {
+            if not_started.fetch_sub(1, Ordering::Relaxed) == 1 {
+                ready.store(true, Ordering::Relaxed)
+            }
+        };
+        let shutdown = Arc::clone(&shutdown);
+        let address = SocketAddr::new(listener_address, port);
+        let handle = tokio::task::spawn_blocking(move || {
+            let limits = ConnectionLimits {
+                finite_connections,
+                n_threads: listener_threads.get(),
+                inflight_limit,
+            };
+            dicom_listener_tcp_loop(
+                address,
+                scp_params,
+                limits,
+                tx_association,
+                on_start,
+                shutdown,
+            )
+            .map_err(|source| RunError::Bind { address, source })
+        });
+        listener_handles.push(handle);
+    }
+    drop(tx_association);
+    // a listener which could not bind its port fails everything right away
+    let listeners = future::try_join_all(
+        listener_handles
+            .into_iter()
+            .map(|handle| async { handle.await? }),
+    );
+
+    let result = tokio::try_join!(
+        listeners,
+        association_series_state_loop(
+            rx_association,
+            tx_storetasks,
+            HandlerOptions {
+                pacs_file: pacs_file_options,
+                dry_run,
+                store: StoreOptions {
+                    on_existing,
+                    verify_write,
+                },
+                seen_index: seen_index.clone(),
+                write_manifest,
+                label,
+                idle_timeout: association_idle_timeout,
+            },
+            FindScu {
+                options: (!disable_findscu).then_some(FindScuOptions {
+                    timeout: findscu_timeout,
+                    retries: findscu_retries,
+                    delay: findscu_delay,
+                    jitter: findscu_jitter,
+                }),
+                cache: FindScuCache::new(
+                    findscu_cache_ttl,
+                    findscu_cache_unknown_ttl,
+                    findscu_cache_size,
+                ),
+                limiter: FindScuLimiter::new(findscu_concurrency),
+            },
+        )
+        .map(|r| r.unwrap())
+        .err_into(),
+        registration_synchronizer(rx_storetasks, tx_register).err_into(),
+        cube_pacsfile_registerer(
+            rx_register,
+            cubedb_client,
+            db.batch_size.get(),
+            quarantine,
+            seen_index
+        )
+        .map(Ok)
+    );
+    if result.is_err() {
+        ready.store(false, Ordering::Relaxed);
+        shutdown.store(true, Ordering::Relaxed);
+        wake_listeners(listener_addresses).await;
+    }
+    shutdown_handle.abort();
+    for handle in [metrics_handle, health_handle].into_iter().flatten() {
+        handle.abort();
+    }
+    let (_, stats, written, registered) = result?;
+    Ok(RunStats {
+        files_written: written.files_written,
+        files_registered: registered.files_registered,
+        errors: written.errors + registered.errors,
+        ..stats
+    })
+}
+
+/// Connect to the database, retrying up to `retries` times, e.g. while it is still starting up.
+///
+/// The time to wait between attempts starts at `interval` and doubles every time, up to
+/// `max_interval`. See [backoff].
+async fn connect_database(
+    db: &DatabaseOptions,
+    retries: usize,
+    interval: Option<Duration>,
+    max_interval: Option<Duration>,
+) -> Result<PgPool, sqlx::Error> {
+    let mut intervals = backoff(interval.unwrap_or_default(), max_interval);
+    let mut attempt = 0;
+    loop {
+        let result = PgPoolOptions::new()
+            .max_connections(db.pool.get())
+            .connect(&db.connection)
+            .await;
+        match result {
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                let wait = intervals.next().unwrap_or_default();
+                tracing::warn!(
+                    attempt,
+                    retries,
+                    message = e.to_string(),
+                    "Could not connect to the database, retrying in {wait:?}."
+                );
+                tokio::time::sleep(wait).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Times to wait between retries, starting at `interval` and doubling every time, up to `max`.
+///
+/// Each time is randomized by up to 25% either way, so that replicas which were started
+/// together do not retry at the same time.
+fn backoff(interval: Duration, max: Option<Duration>) -> impl Iterator<Item = Duration> {
+    let cap = move |d: Duration| max.map(|max| d.min(max)).unwrap_or(d);
+    std::iter::successors(Some(cap(interval)), move |d| Some(cap(d.saturating_mul(2))))
+        .map(|d| d.mul_f64(0.75 + fastrand::f64() / 2.0))
+}
+
+/// Wait for SIGINT or SIGTERM, then set `shutdown` and unset `ready`.
+///
+/// Every [dicom_listener_tcp_loop] is blocked waiting for a connection, so one is made to
+/// each of `listener_addresses` to wake them up.
+async fn shutdown_on_signal(
+    shutdown: Arc<AtomicBool>,
+    ready: Arc<AtomicBool>,
+    listener_addresses: Vec<SocketAddr>,
+) -> std::io::Result<()> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = sigterm.recv() => (),
+    }
+    tracing::info!("Received shutdown signal.");
+    ready.store(false, Ordering::Relaxed);
+    shutdown.store(true, Ordering::Relaxed);
+    wake_listeners(listener_addresses).await;
+    Ok(())
+}
+
+/// Connect to each listener, so that it notices that `shutdown` was set.
+async fn wake_listeners(listener_addresses: Vec<SocketAddr>) {
+    for address in listener_addresses {
+        // a listener which already stopped does not need to be woken up
+        if let Err(e) = TcpStream::connect(connectable(address)).await {
+            tracing::debug!(address = address.to_string(), message = e.to_string());
+        }
+    }
+}
+
+/// Replace an unspecified IP address (`0.0.0.0` or `::`) with the loopback address.
+fn connectable(address: SocketAddr) -> SocketAddr {
+    let ip = match address.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.into(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.into(),
+        ip => ip,
+    };
+    SocketAddr::new(ip, address.port())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    tokio::try_join!(
-        association_series_state_loop(rx_association, tx_storetasks, files_root)
-            .map(|r| r.unwrap()),
-        registration_synchronizer(rx_storetasks, tx_register),
-        cube_pacsfile_registerer(rx_register, cubedb_client, db.batch_size.get())
-    )?;
-    listener_handle.await?
+    #[test]
+    fn test_backoff() {
+        let expected = [1, 2, 4, 8, 10, 10].map(Duration::from_secs);
+        for _ in 0..100 {
+            let actual: Vec<_> = backoff(Duration::from_secs(1), Some(Duration::from_secs(10)))
+                .take(expected.len())
+                .collect();
+            for (actual, expected) in actual.into_iter().zip(expected) {
+                assert!(actual >= expected.mul_f64(0.75));
+                assert!(actual <= expected.mul_f64(1.25));
+            }
+        }
+        let uncapped = backoff(Duration::from_secs(1), None).nth(10).unwrap();
+        assert!(uncapped >= Duration::from_secs(768));
+    }
 }
@@ -1,4 +1,5 @@
 use regex::Regex;
+use std::borrow::Cow;
 use std::sync::OnceLock;
 
 /// Replace disallowed characters with "_".
@@ -7,10 +8,137 @@ use std::sync::OnceLock;
 /// Also, it's necessary to handle NUL bytes...
 pub(crate) fn sanitize_path<S: AsRef<str>>(s: S) -> String {
     let s_nonull = s.as_ref().replace('\0', "");
+    replace_invalid_chars(&s_nonull).into_owned()
+}
+
+/// Like [sanitize_path], but a short hash of the value is appended when any character is replaced.
+///
+/// Different values can become the same after replacing characters (e.g. "Ö" and "Ü" both
+/// become "_"), so the hash keeps the paths of different patients, studies, etc. distinct.
+pub(crate) fn sanitize_path_distinct<S: AsRef<str>>(s: S) -> String {
+    let s_nonull = s.as_ref().replace('\0', "");
+    let sanitized = replace_invalid_chars(&s_nonull);
+    if sanitized == s_nonull {
+        s_nonull
+    } else {
+        let hash = format!("{:x}", seahash::hash(s_nonull.as_bytes()));
+        format!("{}_{}", sanitized, &hash[..7])
+    }
+}
+
+fn replace_invalid_chars(s: &str) -> Cow<'_, str> {
     VALID_CHARS_RE
         .get_or_init(|| Regex::new(r#"[^A-Za-z0-9\.\-]+"#).unwrap())
-        .replace_all(&s_nonull, "_")
-        .to_string()
+        .replace_all(s, "_")
 }
 
 static VALID_CHARS_RE: OnceLock<Regex> = OnceLock::new();
+
+/// Shorten every component of a `/`-separated path to at most `max_len` bytes.
+///
+/// The end of a component which is too long is replaced by a short hash of the whole component,
+/// so that shortened components remain distinct. A `.dcm` or `.dcm.gz` extension is kept.
+pub(crate) fn truncate_path_components(path: &str, max_len: usize) -> String {
+    path.split('/')
+        .map(|component| truncate_component(component, max_len))
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn truncate_component(component: &str, max_len: usize) -> Cow<'_, str> {
+    if component.len() <= max_len {
+        return Cow::Borrowed(component);
+    }
+    let (stem, extension) = [".dcm.gz", ".dcm"]
+        .into_iter()
+        .find_map(|ext| component.strip_suffix(ext).map(|stem| (stem, ext)))
+        .unwrap_or((component, ""));
+    let hash = format!("{:x}", seahash::hash(component.as_bytes()));
+    let suffix = format!("_{}{}", &hash[..7], extension);
+    let mut end = max_len.saturating_sub(suffix.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    Cow::Owned(format!("{}{}", &stem[..end], suffix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("1.2.840.113619", "1.2.840.113619")]
+    #[case("DOE-JOHN", "DOE-JOHN")]
+    #[case("1.2.3\0", "1.2.3")]
+    #[case("", "")]
+    fn test_sanitize_path_unchanged(#[case] value: &str, #[case] expected: &str) {
+        assert_eq!(sanitize_path(value), expected)
+    }
+
+    #[rstest]
+    #[case("MÜLLER^HANS", "M_LLER_HANS")]
+    #[case("DOE JOHN", "DOE_JOHN")]
+    #[case("../etc", ".._etc")]
+    fn test_sanitize_path_changed(#[case] value: &str, #[case] expected: &str) {
+        assert_eq!(sanitize_path(value), expected);
+        let distinct = sanitize_path_distinct(value);
+        assert!(distinct.starts_with(&format!("{expected}_")), "{distinct}");
+        assert_eq!(distinct.len(), expected.len() + 8);
+        assert_eq!(sanitize_path_distinct(value), distinct);
+    }
+
+    #[rstest]
+    #[case("1.2.840.113619")]
+    #[case("DOE-JOHN")]
+    #[case("")]
+    fn test_sanitize_path_distinct_unchanged(#[case] value: &str) {
+        assert_eq!(sanitize_path_distinct(value), value)
+    }
+
+    #[rstest]
+    #[case("MÜLLER^HANS", "MÖLLER^HANS")]
+    #[case("DOE JOHN", "DOE^JOHN")]
+    #[case("DOE JOHN", "DOE_JOHN")]
+    #[case("DOE  JOHN", "DOE JOHN")]
+    fn test_sanitize_path_distinct(#[case] a: &str, #[case] b: &str) {
+        assert_ne!(sanitize_path_distinct(a), sanitize_path_distinct(b))
+    }
+
+    #[rstest]
+    #[case(
+        "SERVICES/PACS/ORTHANC/0001-1.2.3.dcm",
+        255,
+        "SERVICES/PACS/ORTHANC/0001-1.2.3.dcm"
+    )]
+    #[case(
+        "SERVICES/PACS/ORTHANC/0001-1.2.840.113619.dcm",
+        16,
+        "SERVICES/PACS/ORTHANC/0001_f00c233.dcm"
+    )]
+    #[case(
+        "SERVICES/PACS/ORTHANC/0001-1.2.840.113619",
+        16,
+        "SERVICES/PACS/ORTHANC/0001-1.2_57075e2"
+    )]
+    #[case("0001-1.2.840.113619.dcm.gz", 19, "0001_2d58d03.dcm.gz")]
+    #[case("ÜÜÜÜÜÜÜÜÜÜ", 11, "Ü_500c29d")]
+    fn test_truncate_path_components(
+        #[case] path: &str,
+        #[case] max_len: usize,
+        #[case] expected: &str,
+    ) {
+        assert_eq!(truncate_path_components(path, max_len), expected)
+    }
+
+    #[test]
+    fn test_truncate_path_components_distinct() {
+        let a = format!("{}-1.dcm", "X".repeat(300));
+        let b = format!("{}-2.dcm", "X".repeat(300));
+        let a = truncate_path_components(&a, 255);
+        let b = truncate_path_components(&b, 255);
+        assert_eq!(a.len(), 255);
+        assert!(a.ends_with(".dcm"));
+        assert_ne!(a, b)
+    }
+}
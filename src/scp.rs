@@ -3,54 +3,201 @@
 //! File mostly copied from dicom-rs.
 //! https://github.com/Enet4/dicom-rs/blob/dbd41ed3a0d1536747c6b8ea2b286e4c6e8ccc8a/storescp/src/main.rs
 
-use std::collections::HashMap;
-use std::net::TcpStream;
+use camino::{Utf8Path, Utf8PathBuf};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::ErrorKind;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use dicom::core::{DataElement, VR};
 use dicom::dicom_value;
 use dicom::dictionary_std::{tags, StandardDataDictionary};
 use dicom::encoding::TransferSyntaxIndex;
-use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+use dicom::object::{
+    DefaultDicomObject, FileMetaTable, FileMetaTableBuilder, InMemDicomObject, OpenFileOptions,
+};
 use dicom::transfer_syntax::TransferSyntaxRegistry;
-use dicom::ul::association::server::AcceptAny;
-use dicom::ul::pdu::PDataValueType;
+use dicom::ul::association::server::ServerAssociation;
+use dicom::ul::pdu::{PDataValue, PDataValueType};
 use dicom::ul::{Pdu, ServerAssociationOptions};
 use opentelemetry::trace::TraceContextExt;
 use opentelemetry::KeyValue;
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::mpsc::Sender;
 use ulid::Ulid;
 
 use crate::association_error::{AssociationError, AssociationError::*};
-use crate::dicomrs_settings::{ClientAETitle, OurAETitle};
+use crate::dicomrs_settings::{
+    take_called_ae_title, CalledAeTitlePolicy, ClientAETitle, OurAETitle,
+};
 use crate::enums::AssociationEvent;
+use crate::free_space::FreeSpaceGuard;
+use crate::metrics::metrics;
+use crate::pacs_file::{tt, PacsFileOptions, PacsFileRegistrationRequest, StoredData};
+use crate::pacs_limiter::PacsConcurrencyLimiter;
+use crate::spool::SpoolFile;
+use crate::storage::DicomStorage;
+use crate::transfer_syntax_check::{transfer_syntax_mismatch, TransferSyntaxCheck};
+
+/// Parameters of [handle_association] which are the same for every association.
+pub(crate) struct ScpParameters<'a> {
+    /// dicom-rs options for accepting associations
+    pub options: ServerAssociationOptions<'a, CalledAeTitlePolicy>,
+    /// Maximum PDU length
+    pub max_pdu_length: usize,
+    /// Maximum time to wait for the SCU to send something
+    pub timeout: Option<Duration>,
+    /// Maximum time the SCU can spend sending us DICOMs, however active it is
+    pub max_duration: Option<Duration>,
+    /// Our AE title
+    pub aet: OurAETitle,
+    /// Where received DICOM files are stored
+    pub storage: Arc<dyn DicomStorage>,
+    /// Where received DICOM files are stored instead of [ScpParameters::storage]
+    /// when the SCU calls us by one of these AE titles
+    pub called_aet_storage: HashMap<OurAETitle, Arc<dyn DicomStorage>>,
+    /// Addresses of PACS servers which we can query
+    pub pacs_addresses: HashMap<ClientAETitle, String>,
+    /// `pacs_name` of files received from these AE titles, instead of the AE title itself
+    pub pacs_names: HashMap<ClientAETitle, ClientAETitle>,
+    /// AE titles which are allowed to push to us. If empty, all AE titles are allowed.
+    pub allowed_ae_titles: HashSet<ClientAETitle>,
+    /// Associations are rejected if their PACS already has too many associations.
+    pub pacs_concurrency: Option<Arc<PacsConcurrencyLimiter>>,
+    /// Associations are rejected if storage is running out of space.
+    pub free_space: Option<FreeSpaceGuard>,
+    /// Options for extracting DICOM metadata, used to check tags required by _CUBE_
+    pub pacs_file_options: Arc<PacsFileOptions>,
+    /// Whether to keep the received bytes of DICOM instances so that they are stored as-is.
+    pub store_raw: bool,
+    /// If given, received DICOM data is written to a file in this directory as it arrives
+    /// instead of being buffered in memory. Only the header is read back into memory.
+    pub spool_dir: Option<Utf8PathBuf>,
+    /// Initial capacity of the buffer for receiving a DICOM instance in memory
+    pub instance_buffer_capacity: usize,
+    /// The association is aborted if a DICOM instance is larger than this many bytes.
+    pub max_instance_bytes: Option<usize>,
+    /// The association is aborted if it has DICOM instances of more than this many series.
+    pub max_series: Option<usize>,
+    /// What to do with DICOM instances which are not encoded in their negotiated transfer syntax
+    pub transfer_syntax_check: TransferSyntaxCheck,
+    /// What to do with DICOM instances which cannot be read
+    pub unreadable_instances: UnreadableInstances,
+    /// Implementation Class UID written to the file meta group of stored DICOM files
+    pub implementation_class_uid: String,
+}
+
+/// What to do with DICOM instances which cannot be read, e.g. because they are empty.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnreadableInstances {
+    /// Abort the association.
+    #[default]
+    Abort,
+    /// Respond with a C-STORE failure status and continue receiving the association.
+    Reject,
+    /// Like [UnreadableInstances::Reject], and also save the received bytes under
+    /// [crate::storage::REJECTED_DIR_NAME] for troubleshooting.
+    Save,
+}
 
 /// Handle an "association" from an "SCU" (i.e. handle when someone is trying to give us DICOM files).
 ///
 /// The `ulid` parameter should be a unique ULID for this SCU stream instance.
 /// When the association is first established, a [AssociationEvent::Start] event will be sent through `channel`.
 /// For each received DICOM file, it will be sent through the `channel` as [AssociationEvent::DicomInstance].
-pub fn handle_association(
+/// Returns the number of DICOM files which were sent.
+///
+/// If [ScpParameters::timeout] is given and no data is received from the SCU within that time,
+/// the association fails with [AssociationError::Timeout]. A partially received instance is discarded.
+/// Likewise, if the association lasts longer than [ScpParameters::max_duration], it is aborted with
+/// [AssociationError::DurationExceeded]. The DICOM instances which were fully received are kept.
+///
+/// The association is aborted if the AE title is not allowed, its PACS has too many concurrent
+/// associations, there is not enough storage space, a DICOM instance is larger than
+/// [ScpParameters::max_instance_bytes], or a DICOM instance is of a series beyond the first
+/// [ScpParameters::max_series].
+pub(crate) fn handle_association(
     scu_stream: TcpStream,
-    options: &ServerAssociationOptions<AcceptAny>,
-    max_pdu_length: usize,
-    channel: &UnboundedSender<AssociationEvent>,
+    params: &ScpParameters,
+    channel: &Sender<AssociationEvent>,
     ulid: Ulid,
-    aet: &OurAETitle,
-    pacs_addresses: &HashMap<ClientAETitle, String>,
-) -> Result<(), AssociationError> {
-    let mut association = options.establish(scu_stream).map_err(CouldNotEstablish)?;
+) -> Result<usize, AssociationError> {
+    let timeout = params.timeout;
+    // IPv4 clients of a dual-stack listener have IPv4-mapped IPv6 addresses
+    let peer_address = scu_stream
+        .peer_addr()
+        .ok()
+        .map(|address| SocketAddr::new(address.ip().to_canonical(), address.port()));
+    if let Err(e) = scu_stream.set_read_timeout(timeout) {
+        tracing::warn!(association_ulid = ulid.to_string(), message = e.to_string());
+    }
+    let mut association = params
+        .options
+        .establish(scu_stream)
+        .map_err(|e| timeout_or(e, timeout, CouldNotEstablish))?;
+    let started = Instant::now();
     let context = opentelemetry::Context::current();
+    let called_aet = take_called_ae_title();
     let aec = ClientAETitle::from(association.client_ae_title());
-    let pacs_address = pacs_addresses.get(&aec).map(|s| s.to_string());
+    if !params.allowed_ae_titles.is_empty() && !params.allowed_ae_titles.contains(&aec) {
+        context.span().add_event(
+            "rejected_ae_title",
+            vec![KeyValue::new("aec", aec.to_string())],
+        );
+        abort(association, &context);
+        return Err(RejectedAeTitle(aec));
+    }
+    let _pacs_permit = if let Some(limiter) = &params.pacs_concurrency {
+        if let Some(permit) = limiter.try_acquire(&aec) {
+            Some(permit)
+        } else {
+            context.span().add_event(
+                "too_many_associations",
+                vec![KeyValue::new("aec", aec.to_string())],
+            );
+            abort(association, &context);
+            return Err(TooManyAssociations(aec));
+        }
+    } else {
+        None
+    };
+    if let Some(guard) = &params.free_space {
+        if let Some(available) = guard.insufficient() {
+            context.span().add_event(
+                "insufficient_storage",
+                vec![KeyValue::new("available_bytes", available as i64)],
+            );
+            abort(association, &context);
+            return Err(InsufficientStorage {
+                available,
+                required: guard.min_free_bytes(),
+            });
+        }
+    }
+    let pacs_address = params.pacs_addresses.get(&aec).map(|s| s.to_string());
+    let pacs_name = params.pacs_names.get(&aec).unwrap_or(&aec).clone();
     context
         .span()
         .set_attribute(KeyValue::new("aet", aec.to_string()));
+    let storage = if let Some(called_aet) = called_aet {
+        context
+            .span()
+            .set_attribute(KeyValue::new("called_aet", called_aet.to_string()));
+        params.called_aet_storage.get(&called_aet)
+    } else {
+        None
+    }
+    .unwrap_or(&params.storage);
     channel
-        .send(AssociationEvent::Start {
+        .blocking_send(AssociationEvent::Start {
             ulid,
-            aet: aet.clone(),
+            aet: params.aet.clone(),
             aec: aec.clone(),
+            pacs_name,
             pacs_address,
+            peer_address,
+            storage: Arc::clone(storage),
         })
         .unwrap();
 
@@ -59,13 +206,33 @@ pub fn handle_association(
     //     association.presentation_contexts()
     // );
 
-    let mut buffer: Vec<u8> = Vec::with_capacity(max_pdu_length);
-    let mut instance_buffer: Vec<u8> = Vec::with_capacity(1024 * 1024);
+    let mut buffer: Vec<u8> = Vec::with_capacity(params.max_pdu_length);
+    let mut instance_buffer: Vec<u8> = Vec::with_capacity(params.instance_buffer_capacity);
+    let mut instance_bytes: usize = 0;
+    let mut command_buffer: Vec<u8> = Vec::with_capacity(1024);
+    let mut spool: Option<SpoolFile> = None;
     let mut msgid = 1;
+    let mut instances = 0;
     let mut sop_class_uid = "".to_string();
     let mut sop_instance_uid = "".to_string();
+    let mut series_instance_uids: HashSet<String> = HashSet::new();
+    let mut transfer_syntaxes: BTreeSet<String> = BTreeSet::new();
 
-    while let Some(mut pdu) = bubble_no_pdu(association.receive())? {
+    loop {
+        if let Some(max) = params.max_duration.filter(|max| started.elapsed() > *max) {
+            context.span().add_event(
+                "duration_exceeded",
+                vec![KeyValue::new("instances", instances as i64)],
+            );
+            drop(spool);
+            abort(association, &context);
+            return Err(DurationExceeded(max));
+        }
+        let Some(mut pdu) = bubble_no_pdu(association.receive())
+            .map_err(|e| timeout_or(e, timeout, PduReception))?
+        else {
+            break;
+        };
         tracing::trace!("scu ----> scp: {}", pdu.short_description().to_string());
         match pdu {
             Pdu::PData { ref mut data } => {
@@ -75,15 +242,51 @@ pub fn handle_association(
                 }
 
                 for data_value in data {
+                    if data_value.value_type == PDataValueType::Data {
+                        instance_bytes += data_value.data.len();
+                        if let Some(max) = params
+                            .max_instance_bytes
+                            .filter(|max| instance_bytes > *max)
+                        {
+                            context.span().add_event(
+                                "instance_too_large",
+                                vec![KeyValue::new(
+                                    "SOPInstanceUID",
+                                    sop_instance_uid.to_string(),
+                                )],
+                            );
+                            drop(spool);
+                            abort(association, &context);
+                            return Err(InstanceTooLarge(max));
+                        }
+                    }
                     if data_value.value_type == PDataValueType::Data && !data_value.is_last {
-                        instance_buffer.append(&mut data_value.data);
+                        if let Some(spool_dir) = &params.spool_dir {
+                            let ts = presentation_context_ts(&association, data_value)?;
+                            let meta = file_meta(
+                                &sop_class_uid,
+                                &sop_instance_uid,
+                                ts,
+                                &params.implementation_class_uid,
+                                aec.as_str(),
+                            )?;
+                            spool_file(&mut spool, spool_dir, &meta)?.write(&data_value.data)?;
+                        } else {
+                            instance_buffer.append(&mut data_value.data);
+                        }
+                    } else if data_value.value_type == PDataValueType::Command
+                        && !data_value.is_last
+                    {
+                        // command set is fragmented across more than one PDV
+                        command_buffer.append(&mut data_value.data);
                     } else if data_value.value_type == PDataValueType::Command && data_value.is_last
                     {
                         // commands are always in implict VR LE
                         let ts =
                             dicom::transfer_syntax::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
+                        command_buffer.append(&mut data_value.data);
+                        let v = std::mem::take(&mut command_buffer);
                         let data_value = &data_value;
-                        let v = &data_value.data;
 
                         let obj = InMemDicomObject::read_dataset_with_ts(v.as_slice(), &ts)
                             .map_err(FailedToReadCommand)?;
@@ -135,78 +338,172 @@ pub fn handle_association(
                                 .to_string();
                         }
                         instance_buffer.clear();
+                        instance_bytes = 0;
+                        spool = None;
                     } else if data_value.value_type == PDataValueType::Data && data_value.is_last {
-                        instance_buffer.append(&mut data_value.data);
-
-                        let presentation_context = association
-                            .presentation_contexts()
-                            .iter()
-                            .find(|pc| pc.id == data_value.presentation_context_id)
-                            .ok_or(MissingPresentationContext)?;
-                        let ts = &presentation_context.transfer_syntax;
-
-                        let obj = InMemDicomObject::read_dataset_with_ts(
-                            instance_buffer.as_slice(),
-                            TransferSyntaxRegistry.get(ts).unwrap(),
-                        )
-                        .map_err(FailedToReadObject)?;
-                        let file_meta = FileMetaTableBuilder::new()
-                            .media_storage_sop_class_uid(
-                                obj.element(tags::SOP_CLASS_UID)
-                                    .map_err(|_| MissingTag(tags::SOP_CLASS_UID))?
-                                    .to_str()
-                                    .map_err(|_| CouldNotRetrieve(tags::SOP_CLASS_UID))?,
-                            )
-                            .media_storage_sop_instance_uid(
-                                obj.element(tags::SOP_INSTANCE_UID)
-                                    .map_err(|_| MissingTag(tags::SOP_INSTANCE_UID))?
-                                    .to_str()
-                                    .map_err(|_| CouldNotRetrieve(tags::SOP_INSTANCE_UID))?,
-                            )
-                            .transfer_syntax(ts)
-                            .build()
-                            .map_err(FailedToBuildMeta)?;
+                        instance_bytes = 0;
+                        let ts = presentation_context_ts(&association, data_value)?;
+                        record_transfer_syntax(ts, &mut transfer_syntaxes, &context);
+                        let (received, storage) = if let Some(spool_dir) = &params.spool_dir {
+                            let meta = file_meta(
+                                &sop_class_uid,
+                                &sop_instance_uid,
+                                ts,
+                                &params.implementation_class_uid,
+                                aec.as_str(),
+                            )?;
+                            spool_file(&mut spool, spool_dir, &meta)?.write(&data_value.data)?;
+                            let path = spool.take().unwrap().finish()?;
+                            (read_spooled_header(&path), StoredData::Spooled(path))
+                        } else {
+                            instance_buffer.append(&mut data_value.data);
+                            let received = read_buffered_instance(
+                                &instance_buffer,
+                                ts,
+                                &params.implementation_class_uid,
+                                aec.as_str(),
+                            );
+                            let storage = if params.store_raw && received.is_ok() {
+                                StoredData::Raw(std::mem::take(&mut instance_buffer))
+                            } else {
+                                StoredData::Encode
+                            };
+                            (received, storage)
+                        };
+                        let file_obj = match received {
+                            Ok(file_obj) => file_obj,
+                            Err(e) if params.unreadable_instances == UnreadableInstances::Abort => {
+                                storage.discard();
+                                return Err(e);
+                            }
+                            Err(e) => {
+                                tracing::warn!(
+                                    association_ulid = ulid.to_string(),
+                                    aec = aec.as_str(),
+                                    peer_address = peer_address.map(|a| a.to_string()),
+                                    SOPInstanceUID = sop_instance_uid.as_str(),
+                                    error = e.to_string(),
+                                    "DICOM instance could not be read, it is rejected."
+                                );
+                                context.span().add_event(
+                                    "unreadable_instance",
+                                    vec![
+                                        KeyValue::new(
+                                            "SOPInstanceUID",
+                                            sop_instance_uid.to_string(),
+                                        ),
+                                        KeyValue::new("error", e.to_string()),
+                                    ],
+                                );
+                                if params.unreadable_instances == UnreadableInstances::Save {
+                                    // a spooled file is moved to storage instead of being read
+                                    let data = match storage {
+                                        StoredData::Spooled(path) => StoredData::Spooled(path),
+                                        storage => {
+                                            storage.discard();
+                                            StoredData::Raw(std::mem::take(&mut instance_buffer))
+                                        }
+                                    };
+                                    channel
+                                        .blocking_send(AssociationEvent::Unreadable {
+                                            ulid,
+                                            sop_instance_uid: sop_instance_uid.to_string(),
+                                            data,
+                                        })
+                                        .unwrap();
+                                } else {
+                                    storage.discard();
+                                }
+                                send_cstore_response(
+                                    &mut association,
+                                    data_value.presentation_context_id,
+                                    msgid,
+                                    &sop_class_uid,
+                                    &sop_instance_uid,
+                                    STATUS_CANNOT_UNDERSTAND,
+                                )?;
+                                continue;
+                            }
+                        };
+
+                        if let Some(max) = params.max_series {
+                            if let Some(series) = tt(&file_obj, tags::SERIES_INSTANCE_UID) {
+                                if series_instance_uids.len() >= max
+                                    && !series_instance_uids.contains(series)
+                                {
+                                    context.span().add_event(
+                                        "too_many_series",
+                                        vec![KeyValue::new(
+                                            "SeriesInstanceUID",
+                                            series.to_string(),
+                                        )],
+                                    );
+                                    storage.discard();
+                                    abort(association, &context);
+                                    return Err(TooManySeries(max));
+                                }
+                                series_instance_uids.insert(series.to_string());
+                            }
+                        }
 
                         // CALL TO ChRIS-RELATED CODE
                         // --------------------------------------------------------------------------------
-                        let file_obj = obj.with_exact_meta(file_meta);
-                        channel
-                            .send(AssociationEvent::DicomInstance {
-                                ulid,
-                                dcm: file_obj,
-                            })
-                            .unwrap();
+                        let status = cstore_status(params, &aec, peer_address, &file_obj);
+                        if status == STATUS_SUCCESS {
+                            channel
+                                .blocking_send(AssociationEvent::DicomInstance {
+                                    ulid,
+                                    dcm: file_obj,
+                                    storage,
+                                    context: context.clone(),
+                                })
+                                .unwrap();
+                            instances += 1;
+                        } else {
+                            storage.discard();
+                            context.span().add_event(
+                                "cstore_failure",
+                                vec![
+                                    KeyValue::new("SOPInstanceUID", sop_instance_uid.to_string()),
+                                    KeyValue::new("status", status as i64),
+                                ],
+                            );
+                        }
                         // END OF ChRIS-RELATED CODE
                         // --------------------------------------------------------------------------------
 
-                        // send C-STORE-RSP object
-                        // commands are always in implict VR LE
-                        let ts =
-                            dicom::transfer_syntax::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
-
-                        let obj = create_cstore_response(msgid, &sop_class_uid, &sop_instance_uid);
-
-                        let mut obj_data = Vec::new();
-
-                        obj.write_dataset_with_ts(&mut obj_data, &ts)
-                            .map_err(|_| CannotRespond("could not write response object"))?;
-
-                        let pdu_response = Pdu::PData {
-                            data: vec![dicom::ul::pdu::PDataValue {
-                                presentation_context_id: data_value.presentation_context_id,
-                                value_type: PDataValueType::Command,
-                                is_last: true,
-                                data: obj_data,
-                            }],
-                        };
-                        association
-                            .send(&pdu_response)
-                            .map_err(|_| CannotRespond("failed to send response object to SCU"))?;
+                        send_cstore_response(
+                            &mut association,
+                            data_value.presentation_context_id,
+                            msgid,
+                            &sop_class_uid,
+                            &sop_instance_uid,
+                            status,
+                        )?;
                     }
                 }
             }
             Pdu::ReleaseRQ => {
                 buffer.clear();
+                if instance_bytes > 0 {
+                    // the last fragment of the instance was never received, so it is not stored
+                    tracing::warn!(
+                        association_ulid = ulid.to_string(),
+                        aec = aec.as_str(),
+                        SOPInstanceUID = sop_instance_uid.as_str(),
+                        bytes = instance_bytes,
+                        "Association released before the DICOM instance was completely received."
+                    );
+                    context.span().add_event(
+                        "truncated_instance",
+                        vec![
+                            KeyValue::new("SOPInstanceUID", sop_instance_uid.to_string()),
+                            KeyValue::new("bytes", instance_bytes as i64),
+                        ],
+                    );
+                    instance_buffer.clear();
+                    drop(spool.take());
+                }
                 association.send(&Pdu::ReleaseRP).unwrap_or_else(|e| {
                     let a = vec![KeyValue::new("error", e.to_string())];
                     context
@@ -236,13 +533,203 @@ pub fn handle_association(
     } else {
         tracing::info!("Dropping connection with {}", association.client_ae_title());
     }
-    Ok(())
+    Ok(instances)
+}
+
+/// Get the transfer syntax of the presentation context of `data_value`.
+fn presentation_context_ts<'a>(
+    association: &'a ServerAssociation,
+    data_value: &PDataValue,
+) -> Result<&'a str, AssociationError> {
+    association
+        .presentation_contexts()
+        .iter()
+        .find(|pc| pc.id == data_value.presentation_context_id)
+        .map(|pc| pc.transfer_syntax.as_str())
+        .ok_or(MissingPresentationContext)
+}
+
+/// Implementation Class UID written to the file meta group of stored DICOM files by default.
+pub(crate) const IMPLEMENTATION_CLASS_UID: &str = "2.25.6494733337386914071742323352999195111";
+
+/// Implementation Version Name written to the file meta group of stored DICOM files.
+const IMPLEMENTATION_VERSION_NAME: &str = concat!("OXIDICOM_", env!("CARGO_PKG_VERSION"));
+
+/// Count a DICOM instance received in the transfer syntax `ts`, and list the transfer syntaxes
+/// of the association in the `transfer_syntaxes` attribute of its span.
+fn record_transfer_syntax(
+    ts: &str,
+    transfer_syntaxes: &mut BTreeSet<String>,
+    context: &opentelemetry::Context,
+) {
+    metrics()
+        .instances_by_transfer_syntax
+        .with_label_values(&[ts])
+        .inc();
+    if transfer_syntaxes.insert(ts.to_string()) {
+        let value = transfer_syntaxes.iter().cloned().map(Into::into).collect();
+        context.span().set_attribute(KeyValue::new(
+            "transfer_syntaxes",
+            opentelemetry::Value::Array(opentelemetry::Array::String(value)),
+        ));
+    }
+}
+
+/// Create the file meta group for a DICOM instance received from the AE title `aec`.
+fn file_meta(
+    sop_class_uid: &str,
+    sop_instance_uid: &str,
+    ts: &str,
+    implementation_class_uid: &str,
+    aec: &str,
+) -> Result<FileMetaTable, AssociationError> {
+    FileMetaTableBuilder::new()
+        .media_storage_sop_class_uid(sop_class_uid)
+        .media_storage_sop_instance_uid(sop_instance_uid)
+        .transfer_syntax(ts)
+        .implementation_class_uid(implementation_class_uid)
+        .implementation_version_name(IMPLEMENTATION_VERSION_NAME)
+        .source_application_entity_title(aec)
+        .build()
+        .map_err(FailedToBuildMeta)
+}
+
+/// Get the current [SpoolFile], creating it if this is the first fragment of the data set.
+fn spool_file<'a>(
+    spool: &'a mut Option<SpoolFile>,
+    spool_dir: &Utf8Path,
+    meta: &FileMetaTable,
+) -> Result<&'a mut SpoolFile, AssociationError> {
+    if spool.is_none() {
+        *spool = Some(SpoolFile::create(spool_dir, meta)?);
+    }
+    Ok(spool.as_mut().unwrap())
+}
+
+/// Read a spooled DICOM file up to its pixel data.
+fn read_spooled_header(path: &Utf8Path) -> Result<DefaultDicomObject, AssociationError> {
+    OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .open_file(path)
+        .map_err(FailedToOpenSpoolFile)
+}
+
+/// Read a DICOM instance which was received in memory.
+fn read_buffered_instance(
+    instance_buffer: &[u8],
+    ts: &str,
+    implementation_class_uid: &str,
+    aec: &str,
+) -> Result<DefaultDicomObject, AssociationError> {
+    let obj = InMemDicomObject::read_dataset_with_ts(
+        instance_buffer,
+        TransferSyntaxRegistry.get(ts).unwrap(),
+    )
+    .map_err(FailedToReadObject)?;
+    let file_meta = file_meta(
+        obj.element(tags::SOP_CLASS_UID)
+            .map_err(|_| MissingTag(tags::SOP_CLASS_UID))?
+            .to_str()
+            .map_err(|_| CouldNotRetrieve(tags::SOP_CLASS_UID))?
+            .as_ref(),
+        obj.element(tags::SOP_INSTANCE_UID)
+            .map_err(|_| MissingTag(tags::SOP_INSTANCE_UID))?
+            .to_str()
+            .map_err(|_| CouldNotRetrieve(tags::SOP_INSTANCE_UID))?
+            .as_ref(),
+        ts,
+        implementation_class_uid,
+        aec,
+    )?;
+    Ok(obj.with_exact_meta(file_meta))
+}
+
+/// C-STORE status: Success
+const STATUS_SUCCESS: u16 = 0x0000;
+/// C-STORE status: Refused: Out of Resources
+const STATUS_OUT_OF_RESOURCES: u16 = 0xA700;
+/// C-STORE status: Error: Cannot understand
+const STATUS_CANNOT_UNDERSTAND: u16 = 0xC000;
+
+/// Check whether a received DICOM instance can be stored, returning the C-STORE status.
+///
+/// The file is written asynchronously after the C-STORE response is sent, so only failures
+/// which can be known ahead of time are reported: insufficient storage space,
+/// missing or invalid tags which are required to register the file to _CUBE_, and
+/// (if [ScpParameters::transfer_syntax_check] is [TransferSyntaxCheck::Reject])
+/// data which is inconsistent with its transfer syntax.
+fn cstore_status(
+    params: &ScpParameters,
+    aec: &ClientAETitle,
+    peer_address: Option<SocketAddr>,
+    dcm: &DefaultDicomObject,
+) -> u16 {
+    let peer_address = peer_address.map(|address| address.to_string());
+    if let Some(available) = params.free_space.as_ref().and_then(|g| g.insufficient()) {
+        tracing::error!(
+            available_bytes = available,
+            "Refusing DICOM instance, not enough storage space."
+        );
+        return STATUS_OUT_OF_RESOURCES;
+    }
+    if let Err(e) = PacsFileRegistrationRequest::new(aec.clone(), dcm, &params.pacs_file_options) {
+        tracing::error!(
+            aec = aec.as_str(),
+            peer_address = peer_address.as_deref(),
+            message = e.to_string()
+        );
+        return STATUS_CANNOT_UNDERSTAND;
+    }
+    if params.transfer_syntax_check != TransferSyntaxCheck::Off {
+        if let Some(bad_tag) = transfer_syntax_mismatch(dcm) {
+            tracing::warn!(
+                aec = aec.as_str(),
+                peer_address = peer_address.as_deref(),
+                transfer_syntax = dcm.meta().transfer_syntax(),
+                bad_tag = bad_tag.to_string(),
+                "DICOM instance is inconsistent with its transfer syntax."
+            );
+            if params.transfer_syntax_check == TransferSyntaxCheck::Reject {
+                return STATUS_CANNOT_UNDERSTAND;
+            }
+        }
+    }
+    STATUS_SUCCESS
+}
+
+/// Send a C-STORE-RSP with the given `status` to the SCU.
+fn send_cstore_response(
+    association: &mut ServerAssociation,
+    presentation_context_id: u8,
+    msgid: u16,
+    sop_class_uid: &str,
+    sop_instance_uid: &str,
+    status: u16,
+) -> Result<(), AssociationError> {
+    // commands are always in implict VR LE
+    let ts = dicom::transfer_syntax::entries::IMPLICIT_VR_LITTLE_ENDIAN.erased();
+    let obj = create_cstore_response(msgid, sop_class_uid, sop_instance_uid, status);
+    let mut obj_data = Vec::new();
+    obj.write_dataset_with_ts(&mut obj_data, &ts)
+        .map_err(|_| CannotRespond("could not write response object"))?;
+    let pdu_response = Pdu::PData {
+        data: vec![dicom::ul::pdu::PDataValue {
+            presentation_context_id,
+            value_type: PDataValueType::Command,
+            is_last: true,
+            data: obj_data,
+        }],
+    };
+    association
+        .send(&pdu_response)
+        .map_err(|_| CannotRespond("failed to send response object to SCU"))
 }
 
 fn create_cstore_response(
     message_id: u16,
     sop_class_uid: &str,
     sop_instance_uid: &str,
+    status: u16,
 ) -> InMemDicomObject<StandardDataDictionary> {
     InMemDicomObject::command_from_element_iter([
         DataElement::new(
@@ -261,7 +748,7 @@ fn create_cstore_response(
             VR::US,
             dicom_value!(U16, [0x0101]),
         ),
-        DataElement::new(tags::STATUS, VR::US, dicom_value!(U16, [0x0000])),
+        DataElement::new(tags::STATUS, VR::US, dicom_value!(U16, [status])),
         DataElement::new(
             tags::AFFECTED_SOP_INSTANCE_UID,
             VR::UI,
@@ -287,6 +774,31 @@ fn create_cecho_response(message_id: u16) -> InMemDicomObject<StandardDataDictio
     ])
 }
 
+/// If `error` was caused by exceeding the read `timeout`, returns [AssociationError::Timeout].
+/// Otherwise, `error` is wrapped by `otherwise`.
+fn timeout_or(
+    error: dicom::ul::association::server::Error,
+    timeout: Option<Duration>,
+    otherwise: fn(dicom::ul::association::server::Error) -> AssociationError,
+) -> AssociationError {
+    match timeout {
+        Some(duration) if is_timeout(&error) => Timeout(duration),
+        _ => otherwise(error),
+    }
+}
+
+/// Whether the error was caused by a read timeout of the underlying [TcpStream].
+fn is_timeout(error: &(dyn std::error::Error + 'static)) -> bool {
+    let mut source = Some(error);
+    while let Some(e) = source {
+        if let Some(io_error) = e.downcast_ref::<std::io::Error>() {
+            return matches!(io_error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut);
+        }
+        source = e.source();
+    }
+    false
+}
+
 /// Returns `None` if source is [dicom::ul::pdu::reader::Error::NoPduAvailable]
 fn bubble_no_pdu(
     pdu: Result<Pdu, dicom::ul::association::server::Error>,
@@ -303,3 +815,59 @@ fn bubble_no_pdu(
         }
     })
 }
+
+/// Abort the association, noting any failure to do so in the span of `context`.
+fn abort(association: ServerAssociation, context: &opentelemetry::Context) {
+    association.abort().unwrap_or_else(|e| {
+        let a = vec![KeyValue::new("error", e.to_string())];
+        context
+            .span()
+            .add_event("failed_to_send_association_abort", a);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::dictionary_std::uids;
+
+    #[test]
+    fn test_file_meta_provenance() {
+        let element = |tag, vr, value: &str| DataElement::new(tag, vr, PrimitiveValue::from(value));
+        let dcm = InMemDicomObject::from_element_iter([
+            element(tags::SOP_CLASS_UID, VR::UI, uids::CT_IMAGE_STORAGE),
+            element(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5"),
+            element(tags::PATIENT_ID, VR::LO, "1234"),
+        ]);
+        let ts = dicom::transfer_syntax::entries::EXPLICIT_VR_LITTLE_ENDIAN.erased();
+        let mut data = Vec::new();
+        dcm.write_dataset_with_ts(&mut data, &ts).unwrap();
+        let obj = read_buffered_instance(
+            &data,
+            uids::EXPLICIT_VR_LITTLE_ENDIAN,
+            IMPLEMENTATION_CLASS_UID,
+            "ORTHANC",
+        )
+        .unwrap();
+
+        let dir = temp_dir();
+        let path = dir.join("1.2.3.4.5.dcm");
+        obj.write_to_file(&path).unwrap();
+        let written = dicom::object::open_file(&path).unwrap();
+        let meta = written.meta();
+        assert_eq!(meta.implementation_class_uid(), IMPLEMENTATION_CLASS_UID);
+        assert_eq!(
+            meta.implementation_version_name.as_deref(),
+            Some(IMPLEMENTATION_VERSION_NAME)
+        );
+        assert_eq!(
+            meta.source_application_entity_title
+                .as_deref()
+                .map(str::trim_end),
+            Some("ORTHANC")
+        );
+        assert_eq!(meta.media_storage_sop_instance_uid(), "1.2.3.4.5");
+    }
+}
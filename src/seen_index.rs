@@ -0,0 +1,109 @@
+//! A persistent index of DICOM files which were already stored and registered,
+//! so that DICOM instances received again can be skipped.
+use camino::Utf8Path;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions};
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// An SQLite database of the paths of DICOM files which were registered to _CUBE_,
+/// see `OXIDICOM_SEEN_INDEX`.
+///
+/// Files are identified by their path, which contains the `SOPInstanceUID`.
+/// It is safe to use from many tasks at once.
+pub(crate) struct SeenIndex {
+    pool: SqlitePool,
+    /// Entries older than this are ignored. If `None`, entries are kept forever.
+    retention: Option<Duration>,
+}
+
+impl SeenIndex {
+    /// Open the index at `path`, creating it if needed, and remove expired entries.
+    pub async fn open(path: &Utf8Path, retention: Option<Duration>) -> Result<Self, sqlx::Error> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePoolOptions::new().connect_with(options).await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS seen (path TEXT PRIMARY KEY NOT NULL, seen_at INTEGER NOT NULL)",
+        )
+        .execute(&pool)
+        .await?;
+        let index = Self { pool, retention };
+        sqlx::query("DELETE FROM seen WHERE seen_at < ?")
+            .bind(index.cutoff())
+            .execute(&index.pool)
+            .await?;
+        Ok(index)
+    }
+
+    /// Unix time of the oldest entries which are not expired.
+    fn cutoff(&self) -> i64 {
+        self.retention
+            .map(|retention| now() - retention.as_secs() as i64)
+            .unwrap_or(i64::MIN)
+    }
+
+    /// Whether the file at `path` was registered within the retention window.
+    pub async fn contains(&self, path: &str) -> Result<bool, sqlx::Error> {
+        let count: i64 =
+            sqlx::query_scalar("SELECT COUNT(*) FROM seen WHERE path = ? AND seen_at >= ?")
+                .bind(path)
+                .bind(self.cutoff())
+                .fetch_one(&self.pool)
+                .await?;
+        Ok(count > 0)
+    }
+
+    /// Record that the files at `paths` were registered now.
+    pub async fn insert(&self, paths: &[&str]) -> Result<(), sqlx::Error> {
+        let now = now();
+        let mut transaction = self.pool.begin().await?;
+        for path in paths {
+            sqlx::query(
+                "INSERT INTO seen (path, seen_at) VALUES (?, ?) ON CONFLICT (path) DO UPDATE SET seen_at = excluded.seen_at",
+            )
+            .bind(path)
+            .bind(now)
+            .execute(&mut *transaction)
+            .await?;
+        }
+        transaction.commit().await
+    }
+}
+
+fn now() -> i64 {
+    OffsetDateTime::now_utc().unix_timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    #[tokio::test]
+    async fn test_seen_index() {
+        let dir = temp_dir();
+        let path = dir.join("seen.sqlite");
+        let index = SeenIndex::open(&path, None).await.unwrap();
+        assert!(!index.contains("SERVICES/PACS/A/1.dcm").await.unwrap());
+        index.insert(&["SERVICES/PACS/A/1.dcm"]).await.unwrap();
+        assert!(index.contains("SERVICES/PACS/A/1.dcm").await.unwrap());
+        assert!(!index.contains("SERVICES/PACS/A/2.dcm").await.unwrap());
+        index.pool.close().await;
+
+        let reopened = SeenIndex::open(&path, None).await.unwrap();
+        assert!(reopened.contains("SERVICES/PACS/A/1.dcm").await.unwrap());
+        sqlx::query("UPDATE seen SET seen_at = seen_at - 100")
+            .execute(&reopened.pool)
+            .await
+            .unwrap();
+        reopened.pool.close().await;
+
+        let expiring = SeenIndex::open(&path, Some(Duration::from_secs(10)))
+            .await
+            .unwrap();
+        assert!(!expiring.contains("SERVICES/PACS/A/1.dcm").await.unwrap());
+        expiring.pool.close().await;
+    }
+}
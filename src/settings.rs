@@ -1,24 +1,215 @@
 //! Oxidicom settings, which are configurable using environment variables.
-use crate::dicomrs_settings::ClientAETitle;
+use crate::dicomrs_settings::{ClientAETitle, OurAETitle};
+use crate::pacs_file::{DateFormat, SeriesHash};
+use crate::path_template::PathTemplate;
+use crate::scp::{UnreadableInstances, IMPLEMENTATION_CLASS_UID};
+use crate::storage::{Compression, OnExisting};
+use crate::transfer_syntax_check::TransferSyntaxCheck;
 use crate::DicomRsSettings;
 use camino::Utf8PathBuf;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::num::{NonZeroU32, NonZeroUsize};
+use std::time::Duration;
 
 #[derive(Debug, Deserialize)]
 pub struct OxidicomEnvOptions {
     pub db: DatabaseOptions,
+    /// Number of times to retry connecting to the database at startup.
+    #[serde(default)]
+    pub db_connect_retries: usize,
+    /// Time to wait before the first retry of connecting to the database, doubled for every retry.
+    #[serde(
+        default = "default_db_connect_interval",
+        deserialize_with = "deserialize_seconds"
+    )]
+    pub db_connect_interval: Option<Duration>,
+    /// Maximum time to wait between retries of connecting to the database. If `None`,
+    /// the time keeps doubling.
+    #[serde(
+        default = "default_db_connect_max_interval",
+        deserialize_with = "deserialize_seconds"
+    )]
+    pub db_connect_max_interval: Option<Duration>,
     pub files_root: Utf8PathBuf,
+    /// Store DICOM files in an S3 bucket instead of under `files_root`.
+    #[serde(default)]
+    pub s3: Option<S3Options>,
+    /// Template for the paths of received DICOM files. If unset, the pypx format is used.
+    #[serde(default)]
+    pub path_template: Option<PathTemplate>,
+    /// Formats to try when `StudyDate` is neither `YYYYMMDD` nor `YYYY-MM-DD`.
+    #[serde(default)]
+    pub extra_date_formats: Vec<DateFormat>,
+    /// Maximum length in bytes of each component of the paths of received DICOM files.
+    #[serde(default = "default_max_path_component_length")]
+    pub max_path_component_length: NonZeroUsize,
+    /// Compression of stored DICOM files.
+    #[serde(default)]
+    pub compress: Compression,
+    /// Algorithm for the hash in the names of series directories.
+    #[serde(default)]
+    pub series_hash: SeriesHash,
+    /// Append a short hash to values which are changed by sanitization of paths.
+    #[serde(default)]
+    pub sanitize_hash: bool,
+    /// Permissions of stored files, in octal, e.g. `640`. If unset, they depend on the umask.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub file_mode: Option<u32>,
+    /// Permissions of created directories, in octal, e.g. `750`. If unset, they depend on the umask.
+    #[serde(default, deserialize_with = "deserialize_mode")]
+    pub dir_mode: Option<u32>,
+    /// Store DICOM instances which are missing `PatientID` or `StudyDate` using placeholder values.
+    #[serde(default)]
+    pub allow_missing_tags: bool,
+    /// Ask the PACS for `Modality` and `SeriesDescription` when they are missing from DICOM instances.
+    #[serde(default)]
+    pub fill_from_pacs: bool,
+    /// Store DICOM instances exactly as they were received instead of re-encoding them.
+    #[serde(default)]
+    pub store_raw: bool,
+    /// Write received DICOM data to storage as it arrives instead of buffering it in memory.
+    #[serde(default)]
+    pub stream_to_disk: bool,
+    /// Move files which could not be registered to CUBE into a quarantine directory.
+    #[serde(default)]
+    pub quarantine: bool,
+    /// Path of an SQLite database of the DICOM files which were registered. If set, DICOM instances
+    /// which are received again are neither stored nor registered.
+    #[serde(default)]
+    pub seen_index: Option<Utf8PathBuf>,
+    /// How long to remember the files in `seen_index`. If unset, they are remembered forever.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub seen_index_retention: Option<Duration>,
+    /// Write a JSON manifest to the directory of each series received.
+    #[serde(default)]
+    pub write_manifest: bool,
+    /// Label of this deployment, e.g. `env=staging`, written to the manifests.
+    #[serde(default)]
+    pub label: Option<String>,
+    /// Receive DICOM instances without storing nor registering them.
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Replace patient-identifying values and paths in logs with hashes.
+    #[serde(default)]
+    pub log_redact: bool,
     pub scp: DicomRsSettings,
+    /// Reject associations which do not call us by our AE title.
+    #[serde(default)]
+    pub scp_check_called_aet: bool,
     #[serde(default)]
     pub scp_max_pdu_length: usize,
+    /// Initial size of the buffer for receiving a DICOM instance in memory.
+    #[serde(default = "default_instance_buffer_capacity")]
+    pub instance_buffer_capacity: usize,
+    /// Associations are aborted if a DICOM instance is larger than this many bytes.
+    #[serde(default)]
+    pub max_instance_bytes: Option<usize>,
+    /// Associations are aborted if they have DICOM instances of more than this many series.
+    #[serde(default)]
+    pub max_series_per_association: Option<usize>,
+    /// Whether to check that DICOM instances are encoded in their negotiated transfer syntax.
+    #[serde(default)]
+    pub transfer_syntax_check: TransferSyntaxCheck,
+    /// What to do with DICOM instances which cannot be read.
+    #[serde(default)]
+    pub unreadable_instances: UnreadableInstances,
+    /// Maximum number of events from associations waiting to be handled. When full,
+    /// receiving DICOM instances blocks until the queue has room.
+    #[serde(default = "default_association_queue_size")]
+    pub association_queue_size: NonZeroUsize,
+    /// What to do when a DICOM file already exists in storage.
+    #[serde(default)]
+    pub on_existing: OnExisting,
+    /// Whether to read back written DICOM files to check their `SOPInstanceUID`.
+    #[serde(default)]
+    pub verify_write: bool,
+    /// Implementation Class UID written to the file meta group of stored DICOM files.
+    #[serde(default = "default_implementation_class_uid")]
+    pub implementation_class_uid: String,
+    /// Maximum time to wait for a PDU from the SCU before giving up on the association.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub scp_timeout: Option<Duration>,
+    /// Maximum time an association can last before it is aborted, even if data is still being received.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub max_association_duration: Option<Duration>,
+    /// Associations without any events for this long are finished, e.g. if their listener thread died.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub association_idle_timeout: Option<Duration>,
     #[serde(default)]
     pub pacs_address: HashMap<ClientAETitle, String>,
+    /// Names to register as `pacs_name` instead of the AE titles of these PACS.
+    #[serde(default)]
+    pub pacs_name: HashMap<ClientAETitle, ClientAETitle>,
+    /// Never do C-FIND, and use the number of instances received as `NumberOfSeriesRelatedInstances`.
+    #[serde(default)]
+    pub disable_findscu: bool,
+    /// Maximum time to wait for data from a PACS during C-FIND.
+    #[serde(
+        default = "default_findscu_timeout",
+        deserialize_with = "deserialize_seconds"
+    )]
+    pub findscu_timeout: Option<Duration>,
+    /// Number of times to retry a failed C-FIND.
+    #[serde(default = "default_findscu_retries")]
+    pub findscu_retries: usize,
+    /// Time to wait before each C-FIND.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub findscu_delay: Option<Duration>,
+    /// Maximum random time to wait before each C-FIND, in addition to `findscu_delay`.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub findscu_jitter: Option<Duration>,
+    /// Maximum number of concurrent C-FINDs to each PACS.
+    #[serde(default)]
+    pub findscu_concurrency: Option<NonZeroUsize>,
+    /// How long to reuse the result of a C-FIND for a series. If unset, results are not reused.
+    #[serde(default, deserialize_with = "deserialize_seconds")]
+    pub findscu_cache_ttl: Option<Duration>,
+    /// How long to reuse a C-FIND which did not produce a result, at most `findscu_cache_ttl`.
+    #[serde(
+        default = "default_findscu_cache_unknown_ttl",
+        deserialize_with = "deserialize_seconds"
+    )]
+    pub findscu_cache_unknown_ttl: Option<Duration>,
+    /// Maximum number of series to remember C-FIND results for.
+    #[serde(default = "default_findscu_cache_size")]
+    pub findscu_cache_size: NonZeroUsize,
+    /// AE titles which are allowed to push to us. If empty, all AE titles are allowed.
+    #[serde(default)]
+    pub allowed_ae_titles: HashSet<ClientAETitle>,
+    /// Maximum number of concurrent associations from each PACS.
+    #[serde(default)]
+    pub pacs_concurrency: HashMap<ClientAETitle, NonZeroUsize>,
+    /// Maximum number of concurrent associations from a PACS not in `pacs_concurrency`.
+    #[serde(default)]
+    pub pacs_concurrency_default: Option<NonZeroUsize>,
+    /// Associations are rejected if storage has less than this many bytes available.
+    #[serde(default)]
+    pub min_free_bytes: Option<u64>,
     #[serde(default = "default_listener_threads")]
     pub listener_threads: NonZeroUsize,
+    /// Stop accepting connections while this many associations have not been fully received by the writer.
+    #[serde(default)]
+    pub max_inflight_associations: Option<NonZeroUsize>,
+    /// IP address to listen on for DICOM, e.g. `::` to accept both IPv4 and IPv6 connections.
+    #[serde(default = "default_listener_address")]
+    pub listener_address: IpAddr,
     #[serde(default = "default_listener_port")]
     pub listener_port: u16,
+    /// Additional TCP ports to listen on, each with its own AE title and storage root.
+    #[serde(default)]
+    pub listeners: Vec<ListenerOptions>,
+    /// Storage roots for associations which call us by these AE titles, instead of the
+    /// `files_root` of the listener.
+    #[serde(default)]
+    pub called_aet_files_root: HashMap<OurAETitle, Utf8PathBuf>,
+    /// Address to serve Prometheus metrics on. If unset, metrics are not served.
+    #[serde(default)]
+    pub metrics_address: Option<SocketAddr>,
+    /// Address to serve the `/health` check on. If unset, the health check is not served.
+    #[serde(default)]
+    pub health_address: Option<SocketAddr>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -30,6 +221,53 @@ pub struct DatabaseOptions {
     pub batch_size: NonZeroUsize,
 }
 
+/// Configuration of an additional DICOM listener.
+#[derive(Debug, Deserialize)]
+pub struct ListenerOptions {
+    /// TCP port number to listen on
+    pub port: u16,
+    /// Our AE title for associations on this port
+    pub aet: OurAETitle,
+    /// Where DICOM files received on this port are stored
+    pub files_root: Utf8PathBuf,
+}
+
+/// Configuration of S3-compatible object storage.
+///
+/// Credentials are read from `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and `AWS_SESSION_TOKEN`.
+#[derive(Debug, Deserialize)]
+pub struct S3Options {
+    /// Name of the bucket
+    pub bucket: String,
+    /// URL of the S3 service, e.g. `https://s3.us-east-1.amazonaws.com`
+    pub endpoint: String,
+    /// Region of the bucket
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Maximum duration of each request, including its upload
+    #[serde(
+        default = "default_s3_timeout",
+        deserialize_with = "deserialize_seconds"
+    )]
+    pub timeout: Option<Duration>,
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_s3_timeout() -> Option<Duration> {
+    Some(Duration::from_secs(300))
+}
+
+fn default_association_queue_size() -> NonZeroUsize {
+    NonZeroUsize::new(100).unwrap()
+}
+
+fn default_implementation_class_uid() -> String {
+    IMPLEMENTATION_CLASS_UID.to_string()
+}
+
 fn default_pool_size() -> NonZeroU32 {
     NonZeroU32::new(10).unwrap()
 }
@@ -38,10 +276,82 @@ fn default_batch_size() -> NonZeroUsize {
     NonZeroUsize::new(20).unwrap()
 }
 
+fn default_max_path_component_length() -> NonZeroUsize {
+    NonZeroUsize::new(255).unwrap()
+}
+
+fn default_instance_buffer_capacity() -> usize {
+    1024 * 1024
+}
+
+fn default_db_connect_interval() -> Option<Duration> {
+    Some(Duration::from_secs(1))
+}
+
+fn default_db_connect_max_interval() -> Option<Duration> {
+    Some(Duration::from_secs(60))
+}
+
+fn default_findscu_timeout() -> Option<Duration> {
+    Some(Duration::from_secs(30))
+}
+
+fn default_findscu_retries() -> usize {
+    2
+}
+
+fn default_findscu_cache_unknown_ttl() -> Option<Duration> {
+    Some(Duration::from_secs(10))
+}
+
+fn default_findscu_cache_size() -> NonZeroUsize {
+    NonZeroUsize::new(1000).unwrap()
+}
+
 fn default_listener_threads() -> NonZeroUsize {
     NonZeroUsize::new(8).unwrap()
 }
 
+fn default_listener_address() -> IpAddr {
+    Ipv4Addr::UNSPECIFIED.into()
+}
+
 fn default_listener_port() -> u16 {
     11111
 }
+
+/// Deserialize Unix file permissions written in octal, e.g. `0640` or `640`.
+///
+/// The value may be parsed from the environment as a number, in which case its
+/// decimal digits are read as octal digits.
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Number(u32),
+        String(String),
+    }
+    let mode: Option<Mode> = Option::deserialize(deserializer)?;
+    mode.map(|mode| {
+        let digits = match mode {
+            Mode::Number(n) => n.to_string(),
+            Mode::String(s) => s,
+        };
+        u32::from_str_radix(&digits, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid octal mode: {digits}")))
+    })
+    .transpose()
+}
+
+/// Deserialize a number of seconds as a [Duration]. Zero means no value.
+fn deserialize_seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+    let seconds: Option<f64> = Option::deserialize(deserializer)?;
+    seconds
+        .map(|s| Duration::try_from_secs_f64(s).map_err(serde::de::Error::custom))
+        .transpose()
+        .map(|d| d.filter(|d| !d.is_zero()))
+}
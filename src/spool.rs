@@ -0,0 +1,74 @@
+//! Streaming of received DICOM data to temporary files.
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::object::FileMetaTable;
+use std::io::{BufWriter, Write};
+
+/// Name of the directory under `OXIDICOM_FILES_ROOT` where [SpoolFile]s are written.
+///
+/// It must be on the same filesystem as the final location of DICOM files so that they
+/// can be renamed into place.
+pub(crate) const SPOOL_DIR_NAME: &str = ".oxidicom-spool";
+
+/// A DICOM file which is being written as its data is received.
+///
+/// The file is deleted when dropped, unless [SpoolFile::finish] succeeds.
+pub(crate) struct SpoolFile {
+    path: Utf8PathBuf,
+    writer: Option<BufWriter<fs_err::File>>,
+}
+
+impl SpoolFile {
+    /// Create a file in `dir`, writing the DICOM preamble and file meta group.
+    pub fn create(dir: &Utf8Path, meta: &FileMetaTable) -> Result<Self, SpoolError> {
+        let path = dir.join(format!("{}.dcm", ulid::Ulid::new()));
+        let file = fs_err::File::create(&path)?;
+        let mut spool_file = Self {
+            path,
+            writer: Some(BufWriter::new(file)),
+        };
+        let writer = spool_file.writer.as_mut().unwrap();
+        writer.write_all(&[0_u8; 128])?;
+        writer.write_all(b"DICM")?;
+        meta.write(writer)?;
+        Ok(spool_file)
+    }
+
+    /// Append received data set bytes.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), SpoolError> {
+        self.writer.as_mut().unwrap().write_all(data)?;
+        Ok(())
+    }
+
+    /// Flush the file to disk and return its path. It will no longer be deleted on drop.
+    pub fn finish(mut self) -> Result<Utf8PathBuf, SpoolError> {
+        let writer = self.writer.take().unwrap();
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        let result = file.sync_all();
+        if result.is_err() {
+            drop(file);
+            let _ = fs_err::remove_file(&self.path);
+        }
+        result?;
+        Ok(std::mem::take(&mut self.path))
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if self.writer.take().is_some() {
+            if let Err(e) = fs_err::remove_file(&self.path) {
+                tracing::warn!(message = e.to_string());
+            }
+        }
+    }
+}
+
+/// Error writing a [SpoolFile].
+#[derive(thiserror::Error, Debug)]
+pub enum SpoolError {
+    #[error(transparent)]
+    IO(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Meta(#[from] dicom::object::meta::Error),
+}
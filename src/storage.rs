@@ -0,0 +1,471 @@
+//! Where received DICOM files are written to.
+use crate::error::DicomStorageError;
+use crate::metrics::metrics;
+use crate::pacs_file::{PacsFileRegistration, StoredData};
+use camino::{Utf8Path, Utf8PathBuf};
+use dicom::dictionary_std::tags;
+use dicom::object::file::ReadPreamble;
+use dicom::object::{DefaultDicomObject, OpenFileOptions};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{Read, Write};
+
+/// How DICOM files are compressed in storage.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+}
+
+impl Compression {
+    /// Suffix added to the paths of DICOM files, e.g. `.gz`.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+        }
+    }
+}
+
+/// What to do when a DICOM file already exists in storage, see `OXIDICOM_ON_EXISTING`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnExisting {
+    /// Write the DICOM file again.
+    #[default]
+    Overwrite,
+    /// Keep the existing file. The DICOM instance is registered as if it was written.
+    Skip,
+    /// Fail with [DicomStorageError::AlreadyExists].
+    Error,
+}
+
+/// A place where DICOM files can be stored.
+///
+/// Files are identified by their [PacsFileRegistrationRequest::path](crate::pacs_file::PacsFileRegistrationRequest::path),
+/// which is also the path registered to _CUBE_.
+pub(crate) trait DicomStorage: Send + Sync {
+    /// Write a DICOM object, returning where it was written.
+    fn write_dicom(&self, pacs_file: &PacsFileRegistration) -> Result<String, DicomStorageError>;
+
+    /// Write a (small) file which is not a DICOM file, returning where it was written.
+    ///
+    /// A partially written file is never visible at `path`.
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<String, DicomStorageError>;
+
+    /// Move a file which was spooled to disk (see [StoredData::Spooled]) to `path` as it is,
+    /// returning where it was written. The spooled file is deleted either way.
+    fn write_spooled(&self, path: &str, spooled: &Utf8Path) -> Result<String, DicomStorageError>;
+
+    /// Move a file to [QUARANTINE_DIR_NAME], returning where it was moved to.
+    fn quarantine(&self, path: &str) -> Result<String, DicomStorageError>;
+
+    /// Whether a file exists at `path`.
+    fn exists(&self, path: &str) -> Result<bool, DicomStorageError>;
+
+    /// Read the DICOM file at `path` up to its pixel data.
+    fn read_header(&self, path: &str) -> Result<DefaultDicomObject, DicomStorageError>;
+}
+
+/// Options for how the storage task writes DICOM files.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct StoreOptions {
+    /// See `OXIDICOM_ON_EXISTING`
+    pub on_existing: OnExisting,
+    /// Whether to read back written DICOM files to check their `SOPInstanceUID`,
+    /// see `OXIDICOM_VERIFY_WRITE`
+    pub verify_write: bool,
+}
+
+/// Name of the directory where files which could not be registered are moved to,
+/// see `OXIDICOM_QUARANTINE`. The paths of files under it are the same as they would be
+/// outside of it.
+pub(crate) const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+/// Name of the directory where DICOM instances which could not be read are saved to,
+/// see `OXIDICOM_UNREADABLE_INSTANCES`. They are neither stored under `SERVICES/PACS` nor registered.
+pub(crate) const REJECTED_DIR_NAME: &str = "rejected";
+
+/// Permissions of the files and directories created by [FilesystemStorage],
+/// see `OXIDICOM_FILE_MODE` and `OXIDICOM_DIR_MODE`. Ignored on non-Unix platforms.
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct FileModes {
+    /// Mode of files. If `None`, the default of the OS (depending on the umask) is used.
+    pub file: Option<u32>,
+    /// Mode of directories. If `None`, the default of the OS (depending on the umask) is used.
+    pub dir: Option<u32>,
+}
+
+/// Storage of DICOM files in a directory of the local filesystem.
+pub(crate) struct FilesystemStorage {
+    root: Utf8PathBuf,
+    compression: Compression,
+    modes: FileModes,
+}
+
+impl FilesystemStorage {
+    pub fn new(root: Utf8PathBuf, compression: Compression, modes: FileModes) -> Self {
+        Self {
+            root,
+            compression,
+            modes,
+        }
+    }
+
+    /// Create a directory and its missing parents, setting [FileModes::dir] on the ones created.
+    fn create_dir_all(&self, dir: &Utf8Path) -> std::io::Result<()> {
+        let Some(mode) = self.modes.dir else {
+            return fs_err::create_dir_all(dir);
+        };
+        let missing: Vec<_> = dir.ancestors().take_while(|d| !d.is_dir()).collect();
+        fs_err::create_dir_all(dir)?;
+        missing
+            .into_iter()
+            .try_for_each(|created| set_mode(created, mode))
+    }
+
+    /// Set [FileModes::file] on a file.
+    fn set_file_mode(&self, path: &Utf8Path) -> std::io::Result<()> {
+        self.modes
+            .file
+            .map(|mode| set_mode(path, mode))
+            .unwrap_or(Ok(()))
+    }
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Utf8Path, mode: u32) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs_err::set_permissions(path, std::fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Utf8Path, _mode: u32) -> std::io::Result<()> {
+    Ok(())
+}
+
+impl DicomStorage for FilesystemStorage {
+    /// The file is first written to a temporary `.<ULID>.partial` file in the same directory,
+    /// then renamed into place, so that a truncated file is never left at the output path.
+    fn write_dicom(&self, pacs_file: &PacsFileRegistration) -> Result<String, DicomStorageError> {
+        let output_path = self.root.join(&pacs_file.request.path);
+        if let Some(parent_dir) = output_path.parent() {
+            self.create_dir_all(parent_dir)?;
+        }
+        let partial_path = partial_path_of(&output_path);
+        let result =
+            write_partial_dicom(pacs_file, &partial_path, self.compression).and_then(|size| {
+                self.set_file_mode(&partial_path)?;
+                fs_err::rename(&partial_path, &output_path)?;
+                Ok(size)
+            });
+        let size = match result {
+            Ok(size) => size,
+            Err(e) => {
+                if let Err(remove_error) = fs_err::remove_file(&partial_path) {
+                    tracing::warn!(message = remove_error.to_string());
+                }
+                return Err(e);
+            }
+        };
+        metrics().files_written.inc();
+        metrics().bytes_written.inc_by(size);
+        Ok(output_path.into_string())
+    }
+
+    fn write_file(&self, path: &str, data: &[u8]) -> Result<String, DicomStorageError> {
+        let path = self.root.join(path);
+        if let Some(parent) = path.parent() {
+            self.create_dir_all(parent)?;
+        }
+        let partial_path = partial_path_of(&path);
+        let result = fs_err::File::create(&partial_path)
+            .and_then(|mut file| {
+                file.write_all(data)?;
+                file.sync_all()
+            })
+            .and_then(|_| self.set_file_mode(&partial_path))
+            .and_then(|_| fs_err::rename(&partial_path, &path));
+        if let Err(e) = result {
+            let _ = fs_err::remove_file(&partial_path);
+            return Err(e.into());
+        }
+        Ok(path.into_string())
+    }
+
+    fn write_spooled(&self, path: &str, spooled: &Utf8Path) -> Result<String, DicomStorageError> {
+        let path = self.root.join(path);
+        let result = path
+            .parent()
+            .map_or(Ok(()), |parent| self.create_dir_all(parent))
+            .and_then(|_| {
+                // copied if it is on a different filesystem, like in write_partial_dicom
+                if fs_err::rename(spooled, &path).is_err() {
+                    fs_err::copy(spooled, &path)?;
+                    fs_err::remove_file(spooled)?;
+                }
+                self.set_file_mode(&path)
+            });
+        if let Err(e) = result {
+            let _ = fs_err::remove_file(spooled);
+            return Err(e.into());
+        }
+        Ok(path.into_string())
+    }
+
+    fn quarantine(&self, path: &str) -> Result<String, DicomStorageError> {
+        let to = self.root.join(QUARANTINE_DIR_NAME).join(path);
+        if let Some(parent) = to.parent() {
+            self.create_dir_all(parent)?;
+        }
+        fs_err::rename(self.root.join(path), &to)?;
+        Ok(to.into_string())
+    }
+
+    fn exists(&self, path: &str) -> Result<bool, DicomStorageError> {
+        Ok(self.root.join(path).try_exists()?)
+    }
+
+    fn read_header(&self, path: &str) -> Result<DefaultDicomObject, DicomStorageError> {
+        let file = fs_err::File::open(self.root.join(path))?;
+        read_dicom_header(std::io::BufReader::new(file), self.compression)
+    }
+}
+
+/// Read a DICOM file, which is compressed by `compression`, up to its pixel data.
+pub(crate) fn read_dicom_header<R: Read>(
+    from: R,
+    compression: Compression,
+) -> Result<DefaultDicomObject, DicomStorageError> {
+    let options = OpenFileOptions::new()
+        .read_until(tags::PIXEL_DATA)
+        .read_preamble(ReadPreamble::Always);
+    let obj = match compression {
+        Compression::None => options.from_reader(from),
+        Compression::Gzip => options.from_reader(GzDecoder::new(from)),
+    }?;
+    Ok(obj)
+}
+
+/// Write a DICOM object to `path` and flush it to disk, returning the file size.
+///
+/// See [StoredData] for how the DICOM object is written.
+fn write_partial_dicom(
+    pacs_file: &PacsFileRegistration,
+    path: &Utf8Path,
+    compression: Compression,
+) -> Result<u64, DicomStorageError> {
+    if let StoredData::Spooled(spooled_path) = &pacs_file.storage {
+        // if the spooled file cannot be renamed, e.g. because it is on a different filesystem
+        // (see `OXIDICOM_CALLED_AET_FILES_ROOT`), it is copied instead.
+        if compression == Compression::None && fs_err::rename(spooled_path, path).is_ok() {
+            return Ok(fs_err::metadata(path)?.len());
+        }
+    }
+    let mut file = fs_err::File::create(path)?;
+    write_dicom_to(pacs_file, &mut file, compression)?;
+    file.sync_all()?;
+    remove_spooled(pacs_file);
+    Ok(file.metadata()?.len())
+}
+
+/// Write the DICOM file of a [PacsFileRegistration], compressing it if specified.
+pub(crate) fn write_dicom_to<W: Write>(
+    pacs_file: &PacsFileRegistration,
+    to: W,
+    compression: Compression,
+) -> Result<(), DicomStorageError> {
+    match compression {
+        Compression::None => write_uncompressed(pacs_file, to),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(to, flate2::Compression::default());
+            write_uncompressed(pacs_file, &mut encoder)?;
+            encoder.finish()?;
+            Ok(())
+        }
+    }
+}
+
+fn write_uncompressed<W: Write>(
+    pacs_file: &PacsFileRegistration,
+    mut to: W,
+) -> Result<(), DicomStorageError> {
+    match &pacs_file.storage {
+        StoredData::Spooled(spooled_path) => {
+            std::io::copy(&mut fs_err::File::open(spooled_path)?, &mut to)?;
+        }
+        StoredData::Raw(raw) => {
+            let mut writer = std::io::BufWriter::new(to);
+            writer.write_all(&[0_u8; 128])?;
+            writer.write_all(b"DICM")?;
+            pacs_file.obj.meta().write(&mut writer)?;
+            writer.write_all(raw)?;
+            writer.flush()?;
+        }
+        StoredData::Encode => pacs_file.obj.write_all(to)?,
+    }
+    Ok(())
+}
+
+/// Delete the temporary file of a [StoredData::Spooled] which was copied to storage.
+pub(crate) fn remove_spooled(pacs_file: &PacsFileRegistration) {
+    if let StoredData::Spooled(spooled_path) = &pacs_file.storage {
+        if let Err(e) = fs_err::remove_file(spooled_path) {
+            tracing::warn!(message = e.to_string());
+        }
+    }
+}
+
+/// Get the path of the temporary file to write to before it is renamed to `path`.
+///
+/// Its name is short, so it can be created even when the name of `path` is as long
+/// as the file system allows.
+fn partial_path_of(path: &Utf8Path) -> Utf8PathBuf {
+    path.with_file_name(format!(".{}.partial", ulid::Ulid::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pacs_file::tests::{example_dcm, example_options};
+    use crate::pacs_file::PacsFileOptions;
+    use crate::test_util::temp_dir;
+
+    #[test]
+    fn test_write_dicom_to_gzip() {
+        let (pacs_file, _) =
+            PacsFileRegistration::new("ORTHANC".into(), example_dcm("Chest"), &example_options())
+                .unwrap();
+        let mut uncompressed = Vec::new();
+        write_dicom_to(&pacs_file, &mut uncompressed, Compression::None).unwrap();
+        let mut compressed = Vec::new();
+        write_dicom_to(&pacs_file, &mut compressed, Compression::Gzip).unwrap();
+        let mut decompressed = Vec::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(&uncompressed[128..132], b"DICM");
+        assert_eq!(decompressed, uncompressed);
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let root = temp_dir();
+        let storage = FilesystemStorage::new(root.clone(), Compression::None, FileModes::default());
+        storage
+            .write_file("SERVICES/PACS/A/1.dcm", b"DICM")
+            .unwrap();
+        let actual = storage.quarantine("SERVICES/PACS/A/1.dcm").unwrap();
+        assert_eq!(actual, root.join("quarantine/SERVICES/PACS/A/1.dcm"));
+        assert_eq!(fs_err::read(&actual).unwrap(), b"DICM");
+        assert!(!root.join("SERVICES/PACS/A/1.dcm").exists());
+    }
+
+    #[test]
+    fn test_write_spooled() {
+        let root = temp_dir();
+        let storage = FilesystemStorage::new(root.clone(), Compression::None, FileModes::default());
+        let spooled = root.join("spooled");
+        fs_err::write(&spooled, b"garbage").unwrap();
+        let actual = storage.write_spooled("rejected/A/1.2.3", &spooled).unwrap();
+        assert_eq!(actual, root.join("rejected/A/1.2.3"));
+        assert_eq!(fs_err::read(&actual).unwrap(), b"garbage");
+        assert!(!spooled.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_modes() {
+        use std::os::unix::fs::PermissionsExt;
+        let root = temp_dir();
+        let modes = FileModes {
+            file: Some(0o640),
+            dir: Some(0o750),
+        };
+        let storage = FilesystemStorage::new(root.clone(), Compression::None, modes);
+        storage
+            .write_file("SERVICES/PACS/A/1.dcm", b"DICM")
+            .unwrap();
+        let mode_of = |path: &str| {
+            let metadata = fs_err::metadata(root.join(path)).unwrap();
+            metadata.permissions().mode() & 0o7777
+        };
+        assert_eq!(mode_of("SERVICES/PACS/A/1.dcm"), 0o640);
+        assert_eq!(mode_of("SERVICES/PACS/A"), 0o750);
+        assert_eq!(mode_of("SERVICES"), 0o750);
+    }
+
+    #[test]
+    fn test_write_encapsulated_pdf() {
+        use dicom::core::{DataElement, PrimitiveValue, VR};
+        use dicom::dictionary_std::{tags, uids};
+        use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+        // Encapsulated documents have no SeriesNumber, InstanceNumber nor PixelData
+        let element = |tag, vr, value: &str| DataElement::new(tag, vr, PrimitiveValue::from(value));
+        let pdf = b"%PDF-1.4\n%%EOF\n".to_vec();
+        let dcm = InMemDicomObject::from_element_iter([
+            element(tags::SOP_CLASS_UID, VR::UI, uids::ENCAPSULATED_PDF_STORAGE),
+            element(tags::PATIENT_ID, VR::LO, "1234"),
+            element(tags::STUDY_DATE, VR::DA, "20240618"),
+            element(tags::STUDY_INSTANCE_UID, VR::UI, "1.2.3"),
+            element(tags::SERIES_INSTANCE_UID, VR::UI, "1.2.3.4"),
+            element(tags::SOP_INSTANCE_UID, VR::UI, "1.2.3.4.5"),
+            element(tags::MODALITY, VR::CS, "DOC"),
+            element(
+                tags::MIME_TYPE_OF_ENCAPSULATED_DOCUMENT,
+                VR::LO,
+                "application/pdf",
+            ),
+            DataElement::new(
+                tags::ENCAPSULATED_DOCUMENT,
+                VR::OB,
+                PrimitiveValue::from(pdf.clone()),
+            ),
+        ])
+        .with_meta(
+            FileMetaTableBuilder::new()
+                .media_storage_sop_class_uid(uids::ENCAPSULATED_PDF_STORAGE)
+                .media_storage_sop_instance_uid("1.2.3.4.5")
+                .transfer_syntax(uids::EXPLICIT_VR_LITTLE_ENDIAN),
+        )
+        .unwrap();
+        let (pacs_file, bad_tags) =
+            PacsFileRegistration::new("ORTHANC".into(), dcm, &example_options()).unwrap();
+        assert!(bad_tags.is_empty());
+        assert!(pacs_file
+            .request
+            .path
+            .ends_with("/SeriesNumber-SeriesDescription-eaf4f78/InstanceNumber-1.2.3.4.5.dcm"));
+        assert_eq!(pacs_file.request.Modality.as_deref(), Some("DOC"));
+        let root = temp_dir();
+        let storage = FilesystemStorage::new(root.clone(), Compression::None, FileModes::default());
+        let location = storage.write_dicom(&pacs_file).unwrap();
+        let written = dicom::object::open_file(&location).unwrap();
+        let document = written.element(tags::ENCAPSULATED_DOCUMENT).unwrap();
+        assert_eq!(&document.to_bytes().unwrap()[..pdf.len()], pdf.as_slice());
+    }
+
+    #[test]
+    fn test_write_longest_file_name() {
+        let options = PacsFileOptions {
+            path_template: Some(
+                "{SeriesInstanceUID}/{SeriesDescription}-{SOPInstanceUID}.dcm"
+                    .to_string()
+                    .try_into()
+                    .unwrap(),
+            ),
+            ..example_options()
+        };
+        let dcm = example_dcm(&"x".repeat(300));
+        let (pacs_file, _) = PacsFileRegistration::new("ORTHANC".into(), dcm, &options).unwrap();
+        let fname = pacs_file.request.path.rsplit('/').next().unwrap();
+        assert_eq!(fname.len(), 255);
+        let root = temp_dir();
+        let storage = FilesystemStorage::new(root.clone(), Compression::None, FileModes::default());
+        let location = storage.write_dicom(&pacs_file).unwrap();
+        assert!(location.ends_with(fname));
+        assert!(dicom::object::open_file(&location).is_ok());
+    }
+}
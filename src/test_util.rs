@@ -0,0 +1,27 @@
+//! Helpers shared by the tests of several modules.
+use camino::Utf8PathBuf;
+
+/// A new directory for a test, which is deleted when dropped.
+pub(crate) struct TempDir(Utf8PathBuf);
+
+impl std::ops::Deref for TempDir {
+    type Target = Utf8PathBuf;
+
+    fn deref(&self) -> &Utf8PathBuf {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs_err::remove_dir_all(&self.0);
+    }
+}
+
+pub(crate) fn temp_dir() -> TempDir {
+    let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+        .unwrap()
+        .join(format!("oxidicom-test-{}", ulid::Ulid::new()));
+    fs_err::create_dir(&dir).unwrap();
+    TempDir(dir)
+}
@@ -1,15 +1,35 @@
 //! Thread pool implementation from The Book.
 //! https://doc.rust-lang.org/book/ch20-02-multithreaded.html
 
+use crate::metrics::metrics;
+use prometheus::IntGauge;
 use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 type Job = Box<dyn FnOnce() + Send + 'static>;
 
+/// A job and when it was submitted.
+struct QueuedJob {
+    job: Job,
+    queued_at: Instant,
+}
+
+/// A warning is logged when a job waits longer than this for a worker.
+const SATURATED_WAIT: Duration = Duration::from_secs(1);
+
+/// Minimum time between warnings that the pool is saturated.
+const SATURATED_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
 /// Simple thread pool
+///
+/// The number of jobs waiting for a worker is the `thread_pool_queued_jobs` metric. When jobs wait
+/// longer than [SATURATED_WAIT], a warning is logged (at most once every
+/// [SATURATED_WARNING_INTERVAL]), which means that the pool should have more threads.
 pub struct ThreadPool {
     workers: Vec<Worker>,
-    sender: Option<mpsc::Sender<Job>>,
+    sender: Option<mpsc::Sender<QueuedJob>>,
+    queued: IntGauge,
 }
 
 impl ThreadPool {
@@ -21,13 +41,27 @@ impl ThreadPool {
 
         let (sender, receiver) = mpsc::channel();
         let receiver = Arc::new(Mutex::new(receiver));
+        let queued = metrics().thread_pool_queued.with_label_values(&[name]);
+        let saturation = Arc::new(Saturation {
+            name,
+            size,
+            last_warning: Mutex::new(None),
+        });
         let workers = (0..size)
-            .map(|id| Worker::new(id, Arc::clone(&receiver), name))
+            .map(|id| {
+                Worker::new(
+                    id,
+                    Arc::clone(&receiver),
+                    queued.clone(),
+                    Arc::clone(&saturation),
+                )
+            })
             .collect();
 
         ThreadPool {
             workers,
             sender: Some(sender),
+            queued,
         }
     }
 
@@ -36,7 +70,11 @@ impl ThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        let job = QueuedJob {
+            job: Box::new(f),
+            queued_at: Instant::now(),
+        };
+        self.queued.inc();
         self.sender
             .as_ref()
             .expect("thread pool has been shut down")
@@ -44,10 +82,10 @@ impl ThreadPool {
             .unwrap();
     }
 
-    /// Close the thread pool.
+    /// Close the thread pool, waiting for running jobs to finish.
     ///
     /// Note: unlike The Book, the cleanup code is here as a method instead of the Drop trait
-    /// so that CTRL-C aborts threads immediately instead of waiting for them to finish.
+    /// so that dropping the pool (e.g. on panic) does not wait for threads to finish.
     pub fn shutdown(&mut self) {
         drop(self.sender.take());
         for worker in &mut self.workers {
@@ -58,6 +96,33 @@ impl ThreadPool {
     }
 }
 
+/// Rate-limited warnings that the jobs of a [ThreadPool] wait too long for a worker.
+struct Saturation {
+    name: &'static str,
+    size: usize,
+    last_warning: Mutex<Option<Instant>>,
+}
+
+impl Saturation {
+    fn waited(&self, waited: Duration, queued: i64) {
+        if waited < SATURATED_WAIT {
+            return;
+        }
+        let mut last_warning = self.last_warning.lock().unwrap();
+        if last_warning.is_some_and(|last| last.elapsed() < SATURATED_WARNING_INTERVAL) {
+            return;
+        }
+        *last_warning = Some(Instant::now());
+        tracing::warn!(
+            pool = self.name,
+            threads = self.size,
+            waited_seconds = waited.as_secs_f64(),
+            queued,
+            "All threads are busy, jobs are waiting for a worker."
+        );
+    }
+}
+
 struct Worker {
     thread: Option<thread::JoinHandle<()>>,
 }
@@ -65,15 +130,19 @@ struct Worker {
 impl Worker {
     fn new(
         id: usize,
-        receiver: Arc<Mutex<mpsc::Receiver<Job>>>,
-        pool_name: &'static str,
+        receiver: Arc<Mutex<mpsc::Receiver<QueuedJob>>>,
+        queued: IntGauge,
+        saturation: Arc<Saturation>,
     ) -> Worker {
+        let pool_name = saturation.name;
         let thread = thread::spawn(move || {
             tracing::info!("Starting worker {pool_name}/{id}");
             loop {
                 let message = receiver.lock().unwrap().recv();
                 match message {
-                    Ok(job) => {
+                    Ok(QueuedJob { job, queued_at }) => {
+                        queued.dec();
+                        saturation.waited(queued_at.elapsed(), queued.get());
                         job();
                     }
                     Err(_) => {
@@ -89,3 +158,25 @@ impl Worker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queued_jobs() {
+        let mut pool = ThreadPool::new(1, "test_queued_jobs");
+        let (tx_started, rx_started) = mpsc::channel();
+        let (tx_release, rx_release) = mpsc::channel::<()>();
+        pool.execute(move || {
+            tx_started.send(()).unwrap();
+            rx_release.recv().unwrap();
+        });
+        rx_started.recv().unwrap();
+        pool.execute(|| ());
+        assert_eq!(pool.queued.get(), 1);
+        tx_release.send(()).unwrap();
+        pool.shutdown();
+        assert_eq!(pool.queued.get(), 0);
+    }
+}
@@ -0,0 +1,134 @@
+//! Detection of DICOM instances which are not encoded in their negotiated transfer syntax.
+use crate::pacs_file::BadTag;
+use dicom::core::value::Value;
+use dicom::dictionary_std::tags;
+use dicom::encoding::{Codec, TransferSyntaxIndex};
+use dicom::object::DefaultDicomObject;
+use dicom::transfer_syntax::TransferSyntaxRegistry;
+
+/// What to do with DICOM instances which are inconsistent with the transfer syntax of
+/// their presentation context.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferSyntaxCheck {
+    /// Do not check.
+    #[default]
+    Off,
+    /// Log a warning, but store the instance anyway.
+    Warn,
+    /// Refuse to store the instance.
+    Reject,
+}
+
+/// Check that a received DICOM instance is consistent with the transfer syntax of its
+/// file meta group, which is the transfer syntax of the presentation context it was sent in.
+///
+/// An instance is inconsistent if its data set contains a `TransferSyntaxUID` which is
+/// different, or if its `PixelData` is encapsulated when the transfer syntax is native
+/// (or vice versa). `PixelData` is only checked if it was read.
+pub(crate) fn transfer_syntax_mismatch(dcm: &DefaultDicomObject) -> Option<BadTag> {
+    let ts_uid = dcm.meta().transfer_syntax();
+    if let Some(embedded) = dcm
+        .element_opt(tags::TRANSFER_SYNTAX_UID)
+        .ok()
+        .flatten()
+        .and_then(|e| e.to_str().ok())
+    {
+        let embedded = embedded.trim_end_matches(['\0', ' ']);
+        if embedded != ts_uid {
+            return Some(BadTag {
+                tag: tags::TRANSFER_SYNTAX_UID,
+                value: Some(embedded.to_string()),
+            });
+        }
+    }
+    let ts = TransferSyntaxRegistry.get(ts_uid)?;
+    let pixel_data = dcm.element_opt(tags::PIXEL_DATA).ok().flatten()?;
+    let ts_encapsulated = matches!(ts.codec(), Codec::EncapsulatedPixelData(..));
+    let pixel_data_encapsulated = matches!(pixel_data.value(), Value::PixelSequence(_));
+    if ts_encapsulated == pixel_data_encapsulated {
+        return None;
+    }
+    let value = if pixel_data_encapsulated {
+        "encapsulated"
+    } else {
+        "native"
+    };
+    Some(BadTag {
+        tag: tags::PIXEL_DATA,
+        value: Some(value.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dicom::core::value::PixelFragmentSequence;
+    use dicom::core::{DataElement, PrimitiveValue, VR};
+    use dicom::dictionary_std::uids;
+    use dicom::object::{FileMetaTableBuilder, InMemDicomObject};
+    use rstest::*;
+
+    fn dcm_with(ts: &str, elements: Vec<DataElement<InMemDicomObject>>) -> DefaultDicomObject {
+        InMemDicomObject::from_element_iter(elements)
+            .with_meta(
+                FileMetaTableBuilder::new()
+                    .media_storage_sop_class_uid(uids::CT_IMAGE_STORAGE)
+                    .media_storage_sop_instance_uid("1.2.3.4.5")
+                    .transfer_syntax(ts),
+            )
+            .unwrap()
+    }
+
+    fn native_pixel_data() -> DataElement<InMemDicomObject> {
+        DataElement::new(
+            tags::PIXEL_DATA,
+            VR::OW,
+            PrimitiveValue::U16(vec![0; 4].into()),
+        )
+    }
+
+    fn encapsulated_pixel_data() -> DataElement<InMemDicomObject> {
+        DataElement::new(
+            tags::PIXEL_DATA,
+            VR::OB,
+            PixelFragmentSequence::new_fragments(vec![vec![0xFF, 0xD8, 0xFF, 0xD9]]),
+        )
+    }
+
+    #[rstest]
+    #[case(uids::EXPLICIT_VR_LITTLE_ENDIAN, native_pixel_data(), None)]
+    #[case(uids::JPEG_BASELINE8_BIT, encapsulated_pixel_data(), None)]
+    #[case(uids::JPEG_BASELINE8_BIT, native_pixel_data(), Some("native"))]
+    #[case(
+        uids::IMPLICIT_VR_LITTLE_ENDIAN,
+        encapsulated_pixel_data(),
+        Some("encapsulated")
+    )]
+    fn test_pixel_data_mismatch(
+        #[case] ts: &str,
+        #[case] pixel_data: DataElement<InMemDicomObject>,
+        #[case] expected: Option<&str>,
+    ) {
+        let actual = transfer_syntax_mismatch(&dcm_with(ts, vec![pixel_data]));
+        assert_eq!(actual.and_then(|bad| bad.value).as_deref(), expected);
+    }
+
+    #[test]
+    fn test_embedded_transfer_syntax_mismatch() {
+        let element =
+            |ts| DataElement::new(tags::TRANSFER_SYNTAX_UID, VR::UI, PrimitiveValue::from(ts));
+        let same = dcm_with(
+            uids::EXPLICIT_VR_LITTLE_ENDIAN,
+            vec![element("1.2.840.10008.1.2.1\0")],
+        );
+        assert!(transfer_syntax_mismatch(&same).is_none());
+        let different = dcm_with(
+            uids::EXPLICIT_VR_LITTLE_ENDIAN,
+            vec![element(uids::JPEG_BASELINE8_BIT)],
+        );
+        let bad = transfer_syntax_mismatch(&different).unwrap();
+        assert_eq!(bad.tag, tags::TRANSFER_SYNTAX_UID);
+        assert_eq!(bad.value.as_deref(), Some(uids::JPEG_BASELINE8_BIT));
+    }
+}
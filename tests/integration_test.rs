@@ -45,6 +45,9 @@ async fn test_run_everything_from_env() {
         .buffered(4)
         .collect()
         .await;
-    server_handle.await.unwrap().unwrap();
+    let stats = server_handle.await.unwrap().unwrap();
+    assert_eq!(stats.associations, EXAMPLE_SERIES_INSTANCE_UIDS.len());
+    assert_eq!(stats.instances, instances_counts.iter().sum::<usize>());
+    assert_eq!(stats.errors, 0);
     run_assertions(&instances_counts).await;
 }